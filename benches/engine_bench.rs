@@ -1,7 +1,12 @@
 use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
-use kvs::KvStore;
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsServer, NaiveThreadPool, SharedQueueThreadPool, ThreadPool,
+    WorkStealingThreadPool,
+};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
 use tempfile::TempDir;
 
 fn generate_random_bytes(num_chars: usize) -> String {
@@ -24,7 +29,7 @@ fn set_bench(c: &mut Criterion) {
                     let temp_dir = TempDir::new().unwrap();
                     (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
                 },
-                |(mut store, _temp_dir)| {
+                |(store, _temp_dir)| {
                     for i in 1..10000 {
                         let key = format!("key{}", i);
                         let key_len = key.len();
@@ -49,7 +54,7 @@ fn get_bench(c: &mut Criterion) {
             b.iter_batched(
                 || {
                     let temp_dir = TempDir::new().unwrap();
-                    let mut store = KvStore::open(temp_dir.path()).unwrap();
+                    let store = KvStore::open(temp_dir.path()).unwrap();
                     for i in 1..10000 {
                         let key = format!("key{}", i);
                         let key_len = key.len();
@@ -61,11 +66,11 @@ fn get_bench(c: &mut Criterion) {
 
                     (store, temp_dir)
                 },
-                |(mut store, _temp_dir)| {
+                |(store, _temp_dir)| {
                     for i in 1..10000 {
                         let key = format!("key{}", i);
 
-                        store.get(key);
+                        store.get(key).unwrap();
                     }
                 },
                 BatchSize::SmallInput,
@@ -75,5 +80,73 @@ fn get_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// Start a server on a free port, running its connections on `P`. The server
+// thread is never joined; it keeps running until the benchmark exits.
+fn start_server<P: ThreadPool>(store: KvStore, threads: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvsServer::with_pool(store, P::new(threads).unwrap());
+    thread::spawn(move || server.serve(listener));
+    addr
+}
+
+// 8 clients connecting at once, each doing 100 sets and then 100 gets, against
+// a server running its connections on `P` with a varying number of threads
+fn thread_pool_bench<P: ThreadPool>(c: &mut Criterion, name: &str) {
+    let mut group = c.benchmark_group(format!("thread-pool-{}", name));
+    group.sample_size(10);
+    // The servers outlive their benchmark, so keep their directories until
+    // the whole group is done
+    let mut temp_dirs = vec![];
+    for threads in [1, 2, 4, 8].iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = start_server::<P>(KvStore::open(temp_dir.path()).unwrap(), *threads);
+        temp_dirs.push(temp_dir);
+        group.bench_with_input(BenchmarkId::from_parameter(threads), threads, |b, _| {
+            b.iter(|| {
+                let clients: Vec<_> = (0..8)
+                    .map(|client_id| {
+                        thread::spawn(move || {
+                            let mut client = KvsClient::connect(addr).unwrap();
+                            for i in 0..100 {
+                                let key = format!("key{}-{}", client_id, i);
+                                client.set(key, generate_random_bytes(100)).unwrap();
+                            }
+                            for i in 0..100 {
+                                let key = format!("key{}-{}", client_id, i);
+                                client.get(key).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for client in clients {
+                    client.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+    drop(temp_dirs);
+}
+
+fn naive_pool_bench(c: &mut Criterion) {
+    thread_pool_bench::<NaiveThreadPool>(c, "naive");
+}
+
+fn shared_queue_pool_bench(c: &mut Criterion) {
+    thread_pool_bench::<SharedQueueThreadPool>(c, "shared-queue");
+}
+
+fn work_stealing_pool_bench(c: &mut Criterion) {
+    thread_pool_bench::<WorkStealingThreadPool>(c, "work-stealing");
+}
+
+criterion_group!(
+    benches,
+    set_bench,
+    get_bench,
+    naive_pool_bench,
+    shared_queue_pool_bench,
+    work_stealing_pool_bench
+);
 criterion_main!(benches);
@@ -0,0 +1,95 @@
+// `KvsServer` on tokio: it speaks the same protocol to the same `KvsClient`,
+// but serves every connection as a task rather than on a thread, so idle
+// clients cost no thread. The store is reached through `AsyncKvStore`, whose
+// pool does the blocking IO.
+
+use crate::{
+    protocol::{self, Request, Response},
+    server::{backup_path, backups_disabled, ACCEPT_BACKOFF},
+    AsyncKvStore, KvError, Result, SharedQueueThreadPool, ThreadPool,
+};
+use std::path::PathBuf;
+use tokio::{
+    io::{BufReader, BufWriter},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+pub struct AsyncKvsServer<P: ThreadPool = SharedQueueThreadPool> {
+    store: AsyncKvStore<P>,
+    backup_dir: Option<PathBuf>, // None refuses every backup request
+}
+
+impl<P: ThreadPool> AsyncKvsServer<P> {
+    pub fn new(store: AsyncKvStore<P>) -> AsyncKvsServer<P> {
+        AsyncKvsServer {
+            store,
+            backup_dir: None,
+        }
+    }
+
+    // See `KvsServer::backup_dir`
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> AsyncKvsServer<P> {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    // Like `KvsServer::run` and `KvsServer::serve`, see
+    // `server::serve_connections`. Must be run within a tokio runtime.
+    pub async fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr).await?).await
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log::error!("Error accepting a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let store = self.store.clone();
+            let backup_dir = self.backup_dir.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(store, backup_dir, stream).await {
+                    log::error!("Error serving {:?}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn handle_connection<P: ThreadPool>(
+    store: AsyncKvStore<P>,
+    backup_dir: Option<PathBuf>,
+    mut stream: TcpStream,
+) -> Result<()> {
+    let (reader, writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(request) = protocol::read_frame_async(&mut reader).await? {
+        log::debug!("Received {:?}", request);
+        let response = match request {
+            Request::Set { key, value } => store.set(key, value).await.map(|_| None),
+            Request::Get { key } => store.get(key).await,
+            Request::Remove { key } => store.remove(key).await.map(|_| None),
+            Request::Backup { name } => match &backup_dir {
+                Some(dir) => match backup_path(dir, &name) {
+                    Ok(dest) => store.checkpoint(dest).await.map(|_| None),
+                    Err(e) => Err(e),
+                },
+                None => Err(backups_disabled()),
+            },
+        };
+        let response = match response {
+            Ok(value) => Response::Ok(value),
+            Err(KvError::KeyNotFound(key)) => Response::KeyNotFound(key),
+            Err(e) => Response::Err(e.to_string()),
+        };
+        protocol::write_frame_async(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
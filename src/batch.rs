@@ -0,0 +1,49 @@
+// A group of sets and removes applied in one go with `KvsEngine::apply_batch`.
+// `KvStore` writes the whole batch as a single entry in the log, so after a
+// crash either every write in it is there or none is.
+
+use crate::record::Record;
+
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>, // None for a remove
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.ops.push((key.into(), Some(value.into())));
+    }
+
+    // Unlike `KvsEngine::remove`, removing a key that does not exist is not
+    // an error, it is simply skipped when the batch is applied
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) {
+        self.ops.push((key.into(), None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // The writes in the order they were added, later ones winning
+    pub(crate) fn into_ops(self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.ops
+    }
+
+    pub(crate) fn into_records(self) -> Vec<Record> {
+        self.ops
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Record::set(key, value),
+                None => Record::tombstone(key),
+            })
+            .collect()
+    }
+}
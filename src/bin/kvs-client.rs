@@ -0,0 +1,74 @@
+use clap::{Parser, Subcommand};
+use kvs::{KvsClient, Result};
+use std::net::SocketAddr;
+
+// Sends one command to a running `kvs-server`. The output and exit codes are
+// the same as those of `kvs`.
+#[derive(Parser)]
+#[clap(version, about, long_about = None)]
+struct Cli {
+    #[clap(long, global = true, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[clap(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    Set { key: String, value: String },
+    Get { key: String },
+    Rm { key: String },
+    // Have the server write a copy of its store to the directory `name`
+    // under its `--backup-dir`
+    Backup { name: String },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut client = KvsClient::connect(cli.addr)?;
+
+    match cli.command {
+        Commands::Set { key, value } => {
+            let set_result = client.set(key, value);
+
+            if let Err(e) = set_result {
+                println!("Fail to execute SET command because of: {}", e);
+                std::process::exit(1)
+            };
+        }
+        Commands::Get { key } => {
+            let get_result = client.get(key);
+
+            match get_result {
+                Ok(Some(value)) => {
+                    println!("{}", value);
+                }
+                Ok(None) => {
+                    println!("Key not found");
+                }
+                Err(e) => {
+                    println!("Fail to execute GET command because of: {}", e);
+                    std::process::exit(1)
+                }
+            }
+        }
+        Commands::Rm { key } => {
+            let remove_result = client.remove(key);
+
+            if let Err(_e) = remove_result {
+                println!("Key not found");
+                std::process::exit(1);
+            }
+        }
+        Commands::Backup { name } => {
+            let backup_result = client.backup(name);
+
+            if let Err(e) = backup_result {
+                println!("Fail to execute BACKUP command because of: {}", e);
+                std::process::exit(1)
+            };
+        }
+    };
+
+    Ok(())
+}
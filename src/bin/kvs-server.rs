@@ -0,0 +1,159 @@
+use clap::{ArgEnum, Parser};
+use kvs::{
+    AsyncKvStore, AsyncKvsServer, HttpServer, KvStore, KvsServer, MemoryStore, NaiveThreadPool,
+    RespServer, Result, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool,
+};
+use std::process::exit;
+use std::{
+    env::current_dir,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    thread,
+};
+
+// Serves the store in the current directory over TCP to `kvs-client`
+#[derive(Parser)]
+#[clap(version, about, long_about = None)]
+struct Cli {
+    #[clap(long, default_value = "127.0.0.1:4000")]
+    addr: SocketAddr,
+    #[clap(long, arg_enum, default_value = "kvs")]
+    engine: Engine,
+    #[clap(long, arg_enum, default_value = "kvs")]
+    protocol: Protocol,
+    // Also serve the store as an HTTP/JSON API on this address
+    #[clap(long)]
+    http: Option<SocketAddr>,
+    // How connections are run. A connection keeps its thread for as long as
+    // the client stays connected, so the fixed pools serve at most
+    // `--threads` clients at once and queue the others.
+    #[clap(long, arg_enum, default_value = "naive")]
+    pool: Pool,
+    // Threads per fixed pool, by default one per CPU
+    #[clap(long)]
+    threads: Option<usize>,
+    // Serve the kvs protocol on tokio, one task per connection. The pool then
+    // runs the store's blocking calls instead of the connections.
+    #[clap(long = "async")]
+    use_async: bool,
+    // Let `kvs-client backup <name>` write backups of the store to `<name>`
+    // under this directory. Without it, backups are refused.
+    #[clap(long)]
+    backup_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum)]
+enum Engine {
+    Kvs,
+    Memory,
+}
+
+// `resp` speaks the Redis protocol, for redis-cli and Redis client libraries
+#[derive(Clone, Copy, Debug, ArgEnum)]
+enum Protocol {
+    Kvs,
+    Resp,
+}
+
+#[derive(Clone, Copy, Debug, ArgEnum)]
+enum Pool {
+    Naive,
+    SharedQueue,
+    WorkStealing,
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+
+    log::info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    log::info!("Storage engine: {:?}", cli.engine);
+    log::info!("Protocol: {:?}", cli.protocol);
+    log::info!("Thread pool: {:?}", cli.pool);
+    if cli.use_async {
+        log::info!("Serving connections on tokio");
+    }
+    log::info!("Listening on {}", cli.addr);
+
+    match cli.pool {
+        Pool::Naive => run::<NaiveThreadPool>(cli),
+        Pool::SharedQueue => run::<SharedQueueThreadPool>(cli),
+        Pool::WorkStealing => run::<WorkStealingThreadPool>(cli),
+    }
+}
+
+fn run<P: ThreadPool>(cli: Cli) -> Result<()> {
+    let threads = match cli.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism().map_or(4, |threads| threads.get()),
+    };
+
+    let store = match cli.engine {
+        Engine::Kvs => KvStore::open(current_dir()?)?,
+        Engine::Memory => {
+            if let Protocol::Resp = cli.protocol {
+                eprintln!("The resp protocol is only supported by the kvs engine");
+                exit(1);
+            }
+            if cli.http.is_some() {
+                eprintln!("The HTTP API is only supported by the kvs engine");
+                exit(1);
+            }
+            if cli.use_async {
+                eprintln!("The async server is only supported by the kvs engine");
+                exit(1);
+            }
+            if cli.backup_dir.is_some() {
+                eprintln!("Backups are only supported by the kvs engine");
+                exit(1);
+            }
+            return KvsServer::with_pool(MemoryStore::new(), P::new(threads)?).run(cli.addr);
+        }
+    };
+
+    if let Some(http) = cli.http {
+        log::info!("Serving HTTP on {}", http);
+        // Bind here so that a taken address fails the start
+        let listener = TcpListener::bind(http)?;
+        let server = HttpServer::with_pool(store.clone(), P::new(threads)?);
+        thread::spawn(move || {
+            if let Err(e) = server.serve(listener) {
+                log::error!("HTTP server stopped: {}", e);
+            }
+        });
+    }
+
+    if let (Protocol::Resp, Some(_)) = (cli.protocol, &cli.backup_dir) {
+        eprintln!("Backups are only served by the kvs protocol");
+        exit(1);
+    }
+    if let Some(backup_dir) = &cli.backup_dir {
+        log::info!("Backups go to {}", backup_dir.display());
+    }
+
+    let pool = P::new(threads)?;
+    if cli.use_async {
+        if let Protocol::Resp = cli.protocol {
+            eprintln!("The async server only speaks the kvs protocol");
+            exit(1);
+        }
+        let store = AsyncKvStore::with_pool(store, pool);
+        let mut server = AsyncKvsServer::new(store);
+        if let Some(backup_dir) = cli.backup_dir {
+            server = server.backup_dir(backup_dir);
+        }
+        let runtime = tokio::runtime::Runtime::new()?;
+        return runtime.block_on(server.run(cli.addr));
+    }
+
+    match cli.protocol {
+        Protocol::Kvs => {
+            let mut server = KvsServer::with_pool(store, pool);
+            if let Some(backup_dir) = cli.backup_dir {
+                server = server.backup_dir(backup_dir);
+            }
+            server.run(cli.addr)
+        }
+        Protocol::Resp => RespServer::with_pool(store, pool).run(cli.addr),
+    }
+}
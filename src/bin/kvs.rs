@@ -1,30 +1,97 @@
-use clap::{Parser, Subcommand};
-use kvs::{KvStore, Result};
-use std::env::current_dir;
+use clap::{ArgEnum, Parser, Subcommand};
+use kvs::{KvStore, KvsEngine, MemoryStore, Options, Result};
+use std::{
+    env::current_dir,
+    path::{Path, PathBuf},
+};
 
+// The Cli struct holds all the options, positional, and subcommands
 #[derive(Parser)]
 #[clap(version, about, long_about = None)] // This line helps
                                            // to extract the meta information from Cargo.toml
-
-// The Cli struct holds all the options, positional, and subcommands
 struct Cli {
+    #[clap(long, arg_enum, default_value = "kvs")]
+    engine: Engine,
+    // Archive the data files superseded by compaction, see `Options::archive`
+    #[clap(long)]
+    archive: bool,
     #[clap(subcommand)]
     command: Commands,
 }
 
+#[derive(Clone, Copy, ArgEnum)]
+enum Engine {
+    Kvs,
+    Memory,
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    Set { key: String, value: String },
-    Get { key: String },
-    Rm { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Rm {
+        key: String,
+    },
+    // Write a copy of the store that can be opened on its own to `dest`
+    Backup {
+        dest: PathBuf,
+    },
+    // Rebuild the store as it was at a moment in the past into `dest`
+    Restore {
+        // milliseconds since the unix epoch
+        #[clap(long)]
+        until: u64,
+        dest: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
+    // Warnings, like a torn record being discarded on open, go to stderr
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
     let cli = Cli::parse();
 
-    match cli.command {
+    match (cli.engine, cli.command) {
+        (Engine::Kvs, Commands::Backup { dest }) => backup(&dest),
+        (Engine::Kvs, Commands::Restore { until, dest }) => restore(until, &dest),
+        (Engine::Memory, Commands::Backup { .. }) => {
+            println!("Fail to execute BACKUP command because of: the memory engine keeps nothing to back up");
+            std::process::exit(1)
+        }
+        (Engine::Memory, Commands::Restore { .. }) => {
+            println!("Fail to execute RESTORE command because of: the memory engine keeps nothing to restore");
+            std::process::exit(1)
+        }
+        (Engine::Kvs, command) => {
+            let options = Options::new().archive(cli.archive);
+            run(KvStore::open_with(current_dir()?, options)?, command)
+        }
+        (Engine::Memory, command) => run(MemoryStore::new(), command),
+    }
+}
+
+// The store is opened read-only, so a backup can run next to other readers
+// but not while a writer has the directory locked. To back up the store of a
+// running `kvs-server`, use `kvs-client backup` instead.
+fn backup(dest: &Path) -> Result<()> {
+    let backup_result = KvStore::open_with(current_dir()?, Options::new().read_only(true))
+        .and_then(|kv_store| kv_store.checkpoint(dest));
+
+    if let Err(e) = backup_result {
+        println!("Fail to execute BACKUP command because of: {}", e);
+        std::process::exit(1)
+    };
+
+    Ok(())
+}
+
+fn run(kv_store: impl KvsEngine, command: Commands) -> Result<()> {
+    match command {
         Commands::Set { key, value } => {
-            let mut kv_store = KvStore::open(current_dir()?)?;
             let set_result = kv_store.set(key, value);
 
             if let Err(e) = set_result {
@@ -33,7 +100,6 @@ fn main() -> Result<()> {
             };
         }
         Commands::Get { key } => {
-            let mut kv_store = KvStore::open(current_dir()?)?;
             let get_result = kv_store.get(key);
 
             match get_result {
@@ -50,7 +116,6 @@ fn main() -> Result<()> {
             }
         }
         Commands::Rm { key } => {
-            let mut kv_store = KvStore::open(current_dir()?)?;
             let remove_result = kv_store.remove(key);
 
             if let Err(_e) = remove_result {
@@ -58,6 +123,16 @@ fn main() -> Result<()> {
                 std::process::exit(1);
             }
         }
+        Commands::Backup { .. } | Commands::Restore { .. } => unreachable!("handled in main"),
+    };
+
+    Ok(())
+}
+
+fn restore(until: u64, dest: &Path) -> Result<()> {
+    if let Err(e) = KvStore::restore(current_dir()?, dest, until) {
+        println!("Fail to execute RESTORE command because of: {}", e);
+        std::process::exit(1)
     };
 
     Ok(())
@@ -0,0 +1,59 @@
+// The client side of `KvsServer`. One client holds one connection, over which
+// its requests are sent one at a time.
+
+use crate::{
+    protocol::{self, Request, Response},
+    KvError, Result,
+};
+use std::{
+    io::{self, BufReader, BufWriter},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(KvsClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(&Request::Set { key, value }).map(|_| ())
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(&Request::Get { key })
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Remove { key }).map(|_| ())
+    }
+
+    // Have the server back its store up into the directory `name` under its
+    // backup directory, see `Request::Backup`. Unlike `kvs backup`, this
+    // works while the server holds the store.
+    pub fn backup(&mut self, name: impl Into<String>) -> Result<()> {
+        self.request(&Request::Backup { name: name.into() })
+            .map(|_| ())
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        protocol::write_frame(&mut self.writer, request)?;
+        let response = protocol::read_frame(&mut self.reader)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection")
+        })?;
+
+        match response {
+            Response::Ok(value) => Ok(value),
+            Response::KeyNotFound(key) => Err(KvError::KeyNotFound(key)),
+            Response::Err(message) => Err(KvError::Server(message)),
+        }
+    }
+}
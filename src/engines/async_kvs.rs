@@ -0,0 +1,119 @@
+use super::{KvStore, KvsEngine};
+use crate::{Result, SharedQueueThreadPool, ThreadPool, WriteBatch};
+use std::{io, path::PathBuf, sync::Arc, thread, time::Duration};
+use tokio::sync::oneshot;
+
+// A `KvStore` for async code. Every call that may touch the disk runs as a
+// job of a thread pool of its own, and the future it returns waits for the
+// job without blocking the executor. The futures only need the pool, not a
+// particular runtime, so they can be awaited on any executor.
+//
+// Like `KvStore`, it is a cheap handle: clones share the store and the pool.
+// `store` gives access to the blocking API, e.g. for `keys` or `metadata`,
+// which only read the key_dir in memory.
+pub struct AsyncKvStore<P: ThreadPool = SharedQueueThreadPool> {
+    store: KvStore,
+    pool: Arc<P>,
+}
+
+impl AsyncKvStore {
+    // Open the store in `path` on a new pool with one thread per CPU
+    pub async fn open(path: impl Into<PathBuf>) -> Result<AsyncKvStore> {
+        let threads = thread::available_parallelism().map_or(4, |threads| threads.get());
+        let pool = Arc::new(SharedQueueThreadPool::new(threads)?);
+        let path = path.into();
+        let store = run(&*pool, move || KvStore::open(path)).await?;
+        Ok(AsyncKvStore { store, pool })
+    }
+}
+
+impl<P: ThreadPool> AsyncKvStore<P> {
+    // Run the blocking calls to `store` on `pool`
+    pub fn with_pool(store: KvStore, pool: P) -> AsyncKvStore<P> {
+        AsyncKvStore {
+            store,
+            pool: Arc::new(pool),
+        }
+    }
+
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.run(move |store| store.set(key, value)).await
+    }
+
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.run(move |store| store.get(key)).await
+    }
+
+    // Fails with `KvError::KeyNotFound` if the key does not exist
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.run(move |store| store.remove(key)).await
+    }
+
+    pub async fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.run(move |store| store.set_bytes(key, value)).await
+    }
+
+    pub async fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |store| store.get_bytes(&key)).await
+    }
+
+    pub async fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.run(move |store| store.remove_bytes(&key)).await
+    }
+
+    pub async fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.run(move |store| store.set_with_ttl(key, value, ttl))
+            .await
+    }
+
+    pub async fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.run(move |store| store.apply_batch(batch)).await
+    }
+
+    pub async fn sync(&self) -> Result<()> {
+        self.run(|store| store.sync()).await
+    }
+
+    // See `KvStore::checkpoint`
+    pub async fn checkpoint(&self, dest_dir: PathBuf) -> Result<()> {
+        self.run(move |store| store.checkpoint(dest_dir)).await
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&KvStore) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let store = self.store.clone();
+        run(&*self.pool, move || job(&store)).await
+    }
+}
+
+impl<P: ThreadPool> Clone for AsyncKvStore<P> {
+    fn clone(&self) -> AsyncKvStore<P> {
+        AsyncKvStore {
+            store: self.store.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+// Run `job` on `pool` and wait for its result. The job is spawned as soon as
+// the future is first polled; dropping the future does not cancel it.
+async fn run<P: ThreadPool, T: Send + 'static>(
+    pool: &P,
+    job: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let (sender, receiver) = oneshot::channel();
+    pool.spawn(move || {
+        // The future may have been dropped in the meantime
+        let _ = sender.send(job());
+    });
+    // The sender is only dropped without a result if the job panicked
+    receiver
+        .await
+        .map_err(|_| io::Error::other("the store's job panicked"))?
+}
@@ -0,0 +1,1549 @@
+// 1. Write first or compact first?
+// 2. The active data file is also part of the reader files
+// 3. Remove command itself can be deleted (together with the previous set command)
+// 4. Too many files being opened mutliplt times => slows down the db
+
+use super::{Cursor, KvsEngine, Transaction};
+use crate::{
+    hint::{self, HintWriter},
+    lock::DirLock,
+    record::{self, Entry, Record, HEADER_SIZE},
+    KvError, Options, Result, SyncPolicy, WriteBatch,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    mem,
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// Where compaction moves superseded data files to, see `Options::archive`
+const ARCHIVE_DIR: &str = "archive";
+
+// A cheap, cloneable handle to the store. All clones share the same key_dir
+// and data files, so it can be handed out to as many threads as needed.
+//
+// Reads only take the key_dir lock for as long as it takes to look up the
+// location of a record, then read it with a positional read on a shared file
+// handle, so any number of them proceed in parallel. Writes are serialized
+// by the writer mutex. Compaction runs on a background thread and never
+// holds the writer for longer than it takes to roll over the active file.
+// With `SyncPolicy::Interval` another background thread syncs the active file.
+//
+// A store opened read-only has neither a writer nor any background thread.
+#[derive(Clone)]
+pub struct KvStore {
+    dir: Arc<PathBuf>,
+    key_dir: Arc<RwLock<BTreeMap<Vec<u8>, KeyDirValue>>>,
+    readers: Arc<RwLock<HashMap<usize, Arc<File>>>>, // file_id -> reader of that file
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    compactor: Option<Arc<Worker>>,
+    _flusher: Option<Arc<Worker>>, // only held to keep the thread alive
+    options: Arc<Options>,
+    frozen: Option<(usize, u64)>, // for a snapshot: the active file then, and its length
+    _lock: Arc<DirLock>,          // declared last so it is released after the threads are joined
+}
+
+struct KvStoreWriter {
+    dir: Arc<PathBuf>,
+    readers: Arc<RwLock<HashMap<usize, Arc<File>>>>, // the active file is registered here too
+    file_id: usize,          // The file_id of the current active data file for write
+    writer: BufWriter<File>, // the file handle for the active data file
+    write_index: u64,        // the offset at which the next record is appended
+    uncompacted: usize,      // bytes taken by overwritten or removed records
+    disk_size: u64,          // bytes taken by all data files together
+    sync_policy: SyncPolicy,
+    max_file_size: u64,
+    dirty: bool,          // whether the active file has writes that are not synced yet
+    seq: u64,             // the sequence number of the last write since the store was opened
+    broken: bool,         // a failed write could not be cleaned up, see `write`
+    retired_below: usize, // files below this id are merged away by compaction, see `garbage`
+}
+
+// A background thread that can be woken up through `notify`. Dropping the
+// last handle (i.e. the last clone of the store) closes the channel, which
+// tells the thread to exit, and waits for it to finish its current job, so
+// the directory is consistent once the store is gone.
+struct Worker {
+    sender: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+// The state the background compaction thread shares with the store
+struct Compactor {
+    dir: Arc<PathBuf>,
+    key_dir: Arc<RwLock<BTreeMap<Vec<u8>, KeyDirValue>>>,
+    readers: Arc<RwLock<HashMap<usize, Arc<File>>>>,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    archive: bool, // see `Options::archive`
+}
+
+#[derive(Debug, Clone, Copy)]
+struct KeyDirValue {
+    file_id: usize,     // the file_id the record is stored in
+    record_size: usize, // the size of the whole record on disk, header included
+    start_index: u64,
+    timestamp: u64, // when the record was written, in milliseconds since the unix epoch
+    seq: u64,       // the write that produced it, 0 if it was already there on open
+    expires_at: Option<u64>, // see `KvStore::set_with_ttl`
+}
+
+impl KeyDirValue {
+    // Expired keys stay in the key_dir until compaction drops them, but are
+    // treated as absent everywhere
+    fn is_live(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Where and when the live record of a key was written, as returned by
+/// `KvStore::metadata`. It is a copy: compaction may move the record later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// The record is in `<file_id>.log` in the store directory
+    pub file_id: usize,
+    /// The byte offset of the record in that file
+    pub offset: u64,
+    /// The size of the whole record on disk, header included
+    pub record_size: usize,
+    /// When it was written, in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// When it expires, if it was set with a time-to-live
+    pub expires_at: Option<u64>,
+}
+
+impl From<KeyDirValue> for KeyMetadata {
+    fn from(key_dir_value: KeyDirValue) -> KeyMetadata {
+        KeyMetadata {
+            file_id: key_dir_value.file_id,
+            offset: key_dir_value.start_index,
+            record_size: key_dir_value.record_size,
+            timestamp: key_dir_value.timestamp,
+            expires_at: key_dir_value.expires_at,
+        }
+    }
+}
+
+// A summary of the store, as returned by `KvStore::stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvStoreStats {
+    pub keys: usize,              // keys that currently have a value
+    pub data_files: usize,        // `N.log` files in use, the active one included
+    pub disk_size: u64,           // bytes taken by those files together
+    pub uncompacted: Option<u64>, // bytes compaction would reclaim, None if read-only
+}
+
+// Iterator over the keys of a `KvStore` in order, see `KvStore::keys`
+pub struct KvStoreKeys {
+    key_dir: Arc<RwLock<BTreeMap<Vec<u8>, KeyDirValue>>>,
+    cursor: Cursor,
+}
+
+// Scan over a `KvStore`, see `KvsEngine::Scan`
+pub struct KvStoreScan {
+    store: KvStore,
+    cursor: Cursor,
+}
+
+impl KvsEngine for KvStore {
+    type Scan = KvStoreScan;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut writer = self.writer()?.lock().unwrap();
+        self.write_set(&mut writer, Record::set(key, value))
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // 1. Get the meta information from the key_dir, together with the
+        // handle of the file it points to
+        let (key_dir_value, file) = match self.lookup(key) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        // 2. Read the whole record back and verify its checksum
+        read_value(key, &key_dir_value, &file).map(Some)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut writer = self.writer()?.lock().unwrap();
+        self.write_remove(&mut writer, key)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut writer = self.writer()?.lock().unwrap();
+        self.write_batch(&mut writer, batch)
+    }
+
+    fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> KvStoreScan {
+        KvStoreScan {
+            store: self.clone(),
+            cursor: Cursor::new(range),
+        }
+    }
+}
+
+impl KvStore {
+    // `set_bytes` with the writer already locked
+    fn write_set(&self, writer: &mut KvStoreWriter, record: Record) -> Result<()> {
+        // 1. Append the checksummed record
+        let (start_index, record_size) = writer.append(&record)?;
+
+        // 2. If the write is successful, we store the meta information
+        // into the in-memory key_dir
+        let key_dir_value = KeyDirValue {
+            file_id: writer.file_id,
+            record_size,
+            start_index,
+            timestamp: record.timestamp,
+            seq: writer.next_seq(),
+            expires_at: record.expires_at,
+        };
+        let previous = self
+            .key_dir
+            .write()
+            .unwrap()
+            .insert(record.key, key_dir_value);
+        writer.uncompacted += garbage(previous, writer.retired_below);
+
+        // Writer first, then compact. Not the other way around
+        self.maybe_compact(writer);
+
+        Ok(())
+    }
+
+    // `remove_bytes` with the writer already locked
+    fn write_remove(&self, writer: &mut KvStoreWriter, key: &[u8]) -> Result<()> {
+        let exists = self
+            .key_dir
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|key_dir_value| key_dir_value.is_live(record::now_millis()));
+        if !exists {
+            return Err(KvError::KeyNotFound(
+                String::from_utf8_lossy(key).into_owned(),
+            ));
+        }
+
+        // Only drop the key from the key_dir once the tombstone is written
+        let record = Record::tombstone(key.to_vec());
+        let (_, record_size) = writer.append(&record)?;
+        let value = self.key_dir.write().unwrap().remove(key).unwrap();
+
+        // Notice we need to count in both the tombstone & previous Set record
+        writer.uncompacted += record_size + garbage(Some(value), writer.retired_below);
+        self.maybe_compact(writer);
+
+        Ok(())
+    }
+
+    // `apply_batch` with the writer already locked
+    fn write_batch(&self, writer: &mut KvStoreWriter, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        // 1. Write the whole batch as one framed entry
+        let records = batch.into_records();
+        let start_index = writer.append_batch(&records)?;
+
+        // 2. Apply its records to the key_dir in one go, so readers never see
+        // half of the batch either
+        let file_id = writer.file_id;
+        let mut index = start_index + HEADER_SIZE as u64;
+        let mut uncompacted = HEADER_SIZE;
+        {
+            let mut key_dir = self.key_dir.write().unwrap();
+            for record in records {
+                let record_size = record.encoded_len();
+                let seq = writer.next_seq();
+                let retired_below = writer.retired_below;
+                uncompacted +=
+                    apply_record(&mut key_dir, file_id, index, seq, record, retired_below);
+                index += record_size as u64;
+            }
+        }
+
+        writer.uncompacted += uncompacted;
+        self.maybe_compact(writer);
+
+        Ok(())
+    }
+
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, Options::default())
+    }
+
+    pub fn open_with(path: impl Into<PathBuf>, options: Options) -> Result<KvStore> {
+        // 1. Create the directory to the path
+        let dir = path.into();
+        if !dir.is_dir() {
+            if options.read_only || !options.create_if_missing {
+                return Err(KvError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("store directory `{}` does not exist", dir.display()),
+                )));
+            }
+            fs::create_dir_all(&dir)?;
+        }
+
+        // Keep other processes from writing to the same directory. Read-only
+        // stores can share it with each other, but not with a writer.
+        let lock = Arc::new(DirLock::acquire(&dir, !options.read_only)?);
+
+        let mut uncompacted = 0;
+        let mut file_id = 0;
+        let mut readers = HashMap::new();
+        let mut key_dir = BTreeMap::new();
+
+        // 2. when you open the file, you should replay the logs
+        // to mutate the fields of KvStore
+        replay_log(
+            &dir,
+            options.read_only,
+            &mut file_id,
+            &mut readers,
+            &mut key_dir,
+            &mut uncompacted,
+        )?;
+
+        let key_dir = Arc::new(RwLock::new(key_dir));
+        let options = Arc::new(options);
+        if options.read_only {
+            return Ok(KvStore {
+                dir: Arc::new(dir),
+                key_dir,
+                readers: Arc::new(RwLock::new(readers)),
+                writer: None,
+                compactor: None,
+                _flusher: None,
+                options,
+                frozen: None,
+                _lock: lock,
+            });
+        }
+
+        // 3. The active data file is also one of the readers
+        let mut disk_size = 0;
+        for reader in readers.values() {
+            disk_size += reader.metadata()?.len();
+        }
+        let file = open_log(&dir, file_id)?;
+        let write_index = file.metadata()?.len();
+        readers.insert(file_id, Arc::new(File::open(log_path(&dir, file_id))?));
+
+        let dir = Arc::new(dir);
+        let readers = Arc::new(RwLock::new(readers));
+        let writer = KvStoreWriter {
+            dir: dir.clone(),
+            readers: readers.clone(),
+            file_id,
+            writer: BufWriter::new(file),
+            write_index,
+            uncompacted,
+            disk_size: disk_size + write_index,
+            sync_policy: options.sync_policy,
+            max_file_size: options.max_file_size,
+            dirty: false,
+            seq: 0,
+            broken: false,
+            retired_below: 0,
+        };
+
+        let compactor = Compactor {
+            dir,
+            key_dir,
+            readers,
+            writer: Arc::new(Mutex::new(writer)),
+            archive: options.archive,
+        };
+
+        let writer = compactor.writer.clone();
+        let flusher = match options.sync_policy {
+            SyncPolicy::Interval(interval) => {
+                let writer = writer.clone();
+                Some(Arc::new(Worker::spawn(move |receiver| {
+                    run_flusher(&writer, interval, receiver)
+                })))
+            }
+            _ => None,
+        };
+
+        Ok(KvStore {
+            dir: compactor.dir.clone(),
+            key_dir: compactor.key_dir.clone(),
+            readers: compactor.readers.clone(),
+            writer: Some(writer),
+            compactor: Some(Arc::new(Worker::spawn(move |receiver| {
+                compactor.run(receiver)
+            }))),
+            _flusher: flusher,
+            options,
+            frozen: None,
+            _lock: lock,
+        })
+    }
+
+    // Hand every buffered write to the OS. Writes are already flushed before
+    // `set`/`remove` return, so this is only a safety net.
+    pub fn flush(&self) -> Result<()> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap().writer.flush()?),
+            None => Ok(()),
+        }
+    }
+
+    // Force every write made so far to disk, whatever the sync policy
+    pub fn sync(&self) -> Result<()> {
+        match &self.writer {
+            Some(writer) => writer.lock().unwrap().sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Every key in the store, in order. Only the key_dir is read, never the
+    /// data files. Like the scans, it sees writes made while it is in progress.
+    pub fn keys(&self) -> KvStoreKeys {
+        self.range_keys::<&[u8]>(..)
+    }
+
+    // The keys in `range`, in order, like `keys`
+    pub fn range_keys<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> KvStoreKeys {
+        KvStoreKeys {
+            key_dir: self.key_dir.clone(),
+            cursor: Cursor::new(range),
+        }
+    }
+
+    /// Where the live record of `key` is stored, or `None` if it has no value.
+    /// Only the key_dir is read, never the data files.
+    pub fn metadata(&self, key: impl AsRef<[u8]>) -> Option<KeyMetadata> {
+        let key_dir = self.key_dir.read().unwrap();
+        key_dir
+            .get(key.as_ref())
+            .filter(|key_dir_value| key_dir_value.is_live(record::now_millis()))
+            .map(|key_dir_value| (*key_dir_value).into())
+    }
+
+    // Count the keys and data files. Takes the key_dir lock for as long as it
+    // takes to count the keys, which grows with the size of the store.
+    pub fn stats(&self) -> Result<KvStoreStats> {
+        let now = record::now_millis();
+        let keys = {
+            let key_dir = self.key_dir.read().unwrap();
+            key_dir
+                .values()
+                .filter(|key_dir_value| key_dir_value.is_live(now))
+                .count()
+        };
+        let readers: Vec<_> = self.readers.read().unwrap().values().cloned().collect();
+        let mut disk_size = 0;
+        for reader in &readers {
+            disk_size += reader.metadata()?.len();
+        }
+        let uncompacted = self
+            .writer
+            .as_ref()
+            .map(|writer| writer.lock().unwrap().uncompacted as u64);
+
+        Ok(KvStoreStats {
+            keys,
+            data_files: readers.len(),
+            disk_size,
+            uncompacted,
+        })
+    }
+
+    // Set `key` to expire after `ttl`, after which it reads as absent. The
+    // expiry is stored in the record, so it survives a reopen, and the record
+    // is dropped by the first compaction after it expired.
+    pub fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let mut writer = self.writer()?.lock().unwrap();
+        self.write_set(&mut writer, record_with_ttl(key.into(), value.into(), ttl))
+    }
+
+    // Set `key`, optionally with a time-to-live, but only if it currently has
+    // a value (`exists` is `Some(true)`) or has none (`Some(false)`). Returns
+    // whether it was set. This is what the RESP server's SET with its NX/XX
+    // and EX/PX options comes down to.
+    pub(crate) fn set_if(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        exists: Option<bool>,
+    ) -> Result<bool> {
+        let mut writer = self.writer()?.lock().unwrap();
+
+        // Holding the writer keeps the key from changing until we are done
+        if exists.is_some_and(|exists| exists != self.lookup(&key).is_some()) {
+            return Ok(false);
+        }
+
+        let record = match ttl {
+            Some(ttl) => record_with_ttl(key, value, ttl),
+            None => Record::set(key, value),
+        };
+        self.write_set(&mut writer, record)?;
+        Ok(true)
+    }
+
+    // Replace the value of `key` with `new` if it currently is `expected`,
+    // where `None` stands for no value on both sides. Returns whether the swap
+    // happened. No other write can get in between the check and the swap.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        let mut writer = self.writer()?.lock().unwrap();
+
+        // Holding the writer keeps the key from changing until we are done
+        let current = self.get_bytes(key)?;
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(new) => self.write_set(&mut writer, Record::set(key.to_vec(), new.to_vec()))?,
+            None if current.is_some() => self.write_remove(&mut writer, key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    // Set `key` only if it has no value yet. Returns whether it was set.
+    pub fn set_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    // Remove `key` only if its value is `expected`. Returns whether it was
+    // removed.
+    pub fn remove_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    // Write a consistent copy of the store to `dest_dir`, which can then be
+    // opened on its own. Writes are only held up while the files to copy are
+    // picked, not while they are copied. Immutable data files are hard-linked
+    // into `dest_dir` where possible and copied otherwise; the active file is
+    // copied up to the last write made before the checkpoint, or for a
+    // snapshot, before the snapshot was taken. A read-only store has no
+    // active file, but its newest one is copied too: opening the copy may
+    // cut a torn tail off it, which must not happen to the original.
+    //
+    // `dest_dir` is created if needed and must not contain anything yet.
+    pub fn checkpoint(&self, dest_dir: impl AsRef<Path>) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        create_empty_dir(dest_dir)?;
+
+        // 1. With writes held up, take the length of the active file and a
+        // handle to every data file. The handles keep the files readable even
+        // if a compaction deletes them while they are being copied.
+        let (active, files) = {
+            let mut writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+            let active = match writer.as_deref_mut() {
+                Some(writer) => {
+                    writer.writer.flush()?;
+                    Some((writer.file_id, writer.write_index))
+                }
+                None => self.frozen,
+            };
+            let files = self.readers.read().unwrap().clone();
+            (active, files)
+        };
+        let active = match active {
+            Some(active) => Some(active),
+            None => match files.iter().max_by_key(|(&file_id, _)| file_id) {
+                Some((&file_id, file)) => Some((file_id, file.metadata()?.len())),
+                None => None,
+            },
+        };
+
+        // 2. Link or copy them over, together with their hints
+        for (file_id, file) in files {
+            let dest_path = log_path(dest_dir, file_id);
+            match active {
+                Some((active_id, len)) if active_id == file_id => {
+                    copy_file(&file, &dest_path, len)?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let dir = &self.dir;
+            if fs::hard_link(log_path(dir, file_id), &dest_path).is_err() {
+                copy_file(&file, &dest_path, file.metadata()?.len())?;
+            }
+            // A hint is only an optimization: without it the copy gets replayed
+            let hint_path = hint::hint_path(dir, file_id);
+            if fs::hard_link(&hint_path, hint::hint_path(dest_dir, file_id)).is_err() {
+                let _ = fs::copy(&hint_path, hint::hint_path(dest_dir, file_id));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Rebuild the store in `dir` as it was at `until` (in milliseconds since
+    // the unix epoch) as a new store in `dest_dir`, by replaying its archived
+    // data files (see `Options::archive`) and its current ones, leaving out
+    // everything written after that moment. Values that were overwritten or
+    // removed since can only be brought back if compaction archived the files
+    // holding them rather than deleting them.
+    //
+    // `dir` must not be open for writing. `dest_dir` is created if needed and
+    // must not contain anything yet.
+    pub fn restore(dir: impl AsRef<Path>, dest_dir: impl AsRef<Path>, until: u64) -> Result<()> {
+        let dir = dir.as_ref();
+        let dest_dir = dest_dir.as_ref();
+        let _lock = DirLock::acquire(dir, false)?;
+        create_empty_dir(dest_dir)?;
+
+        // 1. Collect every data file, archived or not. File ids are never
+        // reused, so the ids alone put them in the order they were written.
+        let mut paths = BTreeMap::new();
+        for dir in [dir.join(ARCHIVE_DIR), dir.to_path_buf()] {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let id = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".log"))
+                    .and_then(|id| id.parse::<usize>().ok());
+                if let Some(id) = id {
+                    paths.insert(id, entry.path());
+                }
+            }
+        }
+        let newest = paths.keys().next_back().copied();
+
+        // 2. Replay them into a key_dir, skipping the entries written after
+        // `until`. A batch is only replayed if all of it was written by then.
+        let mut key_dir = BTreeMap::new();
+        let mut files = HashMap::new();
+        for (id, path) in paths {
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut index = 0;
+            loop {
+                let entry = match Entry::read_from(&mut reader) {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(e)
+                        if e.kind() == io::ErrorKind::InvalidData
+                            || e.kind() == io::ErrorKind::UnexpectedEof =>
+                    {
+                        // A torn tail of the newest file was never acknowledged
+                        let file = reader.get_ref();
+                        let len = file.metadata()?.len();
+                        if Some(id) == newest && is_torn_tail(file, index, len)? {
+                            break;
+                        }
+                        return Err(KvError::Corruption {
+                            file_id: id,
+                            offset: index,
+                        });
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                index += entry.overhead() as u64;
+                let records = match entry {
+                    Entry::Record(record) => vec![record],
+                    Entry::Batch(records) => records,
+                };
+                let in_time = records.iter().all(|record| record.timestamp <= until);
+                for record in records {
+                    let record_size = record.encoded_len();
+                    if in_time {
+                        apply_record(&mut key_dir, id, index, 0, record, 0);
+                    }
+                    index += record_size as u64;
+                }
+            }
+            files.insert(id, reader.into_inner());
+        }
+
+        // 3. Copy the surviving records to the first data file of the new
+        // store, with a hint so it opens without replaying
+        let mut dest = BufWriter::new(File::create(log_path(dest_dir, 0))?);
+        let mut hint = HintWriter::default();
+        let mut start_index = 0;
+        let mut buf = vec![];
+        for (key, record) in &key_dir {
+            buf.resize(record.record_size, 0);
+            read_at(&files[&record.file_id], &mut buf, record.start_index)?;
+            dest.write_all(&buf)?;
+            hint.add(
+                record.timestamp,
+                key,
+                record.record_size,
+                start_index,
+                record.expires_at,
+            );
+            start_index += record.record_size as u64;
+        }
+        dest.flush()?;
+        dest.get_ref().sync_all()?;
+        hint.finish(dest_dir, 0, start_index)?;
+
+        Ok(())
+    }
+
+    // A read-only view of the store as it is right now: writes made after
+    // this, including compactions, do not show through it. The snapshot holds
+    // a copy of the key_dir and its own handles to the data files, so the files
+    // it reads from stay readable even once compaction deleted them, until it
+    // is dropped. Copying the key_dir takes memory in proportion to the number
+    // of keys.
+    //
+    // The snapshot behaves like a store opened read-only: `set`, `remove` and
+    // the other writes fail with `KvError::ReadOnly`. Like any clone of the
+    // store, it keeps the directory locked while it is alive.
+    pub fn snapshot(&self) -> KvStore {
+        // Holding writes up, so the length of the active file matches the
+        // key_dir. Every file the key_dir points at is registered with the
+        // readers before the key_dir is, so the copies are consistent.
+        let writer = self.writer.as_ref().map(|writer| writer.lock().unwrap());
+        let frozen = match writer.as_deref() {
+            Some(writer) => Some((writer.file_id, writer.write_index)),
+            None => self.frozen,
+        };
+        let key_dir = self.key_dir.read().unwrap();
+        let readers = self.readers.read().unwrap();
+        KvStore {
+            dir: self.dir.clone(),
+            key_dir: Arc::new(RwLock::new(key_dir.clone())),
+            readers: Arc::new(RwLock::new(readers.clone())),
+            writer: None,
+            compactor: None,
+            _flusher: None,
+            options: self.options.clone(),
+            frozen,
+            _lock: self._lock.clone(),
+        }
+    }
+
+    // Start an optimistic transaction, see `Transaction`
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.clone())
+    }
+
+    // The value of `key` together with the sequence number of the write that
+    // produced it
+    pub(super) fn get_with_seq(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        let (key_dir_value, file) = match self.lookup(key) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let value = read_value(key, &key_dir_value, &file)?;
+        Ok(Some((value, key_dir_value.seq)))
+    }
+
+    // Apply `batch` only if every key in `reads` still has the sequence number
+    // it was read with (`None` for a key that had no value)
+    pub(super) fn commit(
+        &self,
+        reads: &HashMap<Vec<u8>, Option<u64>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        // Holding the writer keeps every other write out until the batch is in
+        let mut writer = match &self.writer {
+            Some(writer) => writer.lock().unwrap(),
+            // nothing can change under a read-only store
+            None if batch.is_empty() => return Ok(()),
+            None => return Err(KvError::ReadOnly),
+        };
+
+        {
+            let key_dir = self.key_dir.read().unwrap();
+            let now = record::now_millis();
+            for (key, seq) in reads {
+                let current = key_dir
+                    .get(key)
+                    .filter(|key_dir_value| key_dir_value.is_live(now))
+                    .map(|key_dir_value| key_dir_value.seq);
+                if current != *seq {
+                    return Err(KvError::Conflict(String::from_utf8_lossy(key).into_owned()));
+                }
+            }
+        }
+
+        self.write_batch(&mut writer, batch)
+    }
+
+    // The key_dir entry of `key` if it is live, together with the handle of
+    // the file it points to. Both are looked up under the key_dir lock, so
+    // compaction cannot retire the file in between.
+    fn lookup(&self, key: &[u8]) -> Option<(KeyDirValue, Arc<File>)> {
+        let key_dir = self.key_dir.read().unwrap();
+        let key_dir_value = *key_dir.get(key)?;
+        if !key_dir_value.is_live(record::now_millis()) {
+            return None;
+        }
+        Some((key_dir_value, self.reader(&key_dir_value)))
+    }
+
+    fn reader(&self, key_dir_value: &KeyDirValue) -> Arc<File> {
+        self.readers.read().unwrap()[&key_dir_value.file_id].clone()
+    }
+
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        self.writer.as_deref().ok_or(KvError::ReadOnly)
+    }
+
+    // Wake up the compactor once enough of the data files is garbage
+    fn maybe_compact(&self, writer: &KvStoreWriter) {
+        let uncompacted = writer.uncompacted as u64;
+        if self.options.should_compact(uncompacted, writer.disk_size) {
+            if let Some(compactor) = &self.compactor {
+                compactor.notify();
+            }
+        }
+    }
+}
+
+impl KvStoreScan {
+    // Look up the next key under the key_dir lock like `get` does, then read
+    // its value without holding any lock
+    fn read(
+        &mut self,
+        step: impl FnOnce(
+            &mut Cursor,
+            &BTreeMap<Vec<u8>, KeyDirValue>,
+        ) -> Option<(Vec<u8>, KeyDirValue)>,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let (key, key_dir_value, file) = {
+            let key_dir = self.store.key_dir.read().unwrap();
+            let (key, key_dir_value) = step(&mut self.cursor, &key_dir)?;
+            let file = self.store.reader(&key_dir_value);
+            (key, key_dir_value, file)
+        };
+
+        Some(read_value(&key, &key_dir_value, &file).map(|value| (key, value)))
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = record::now_millis();
+        self.read(|cursor, key_dir| loop {
+            let (key, key_dir_value) = cursor.next(key_dir)?;
+            if key_dir_value.is_live(now) {
+                return Some((key.clone(), *key_dir_value));
+            }
+        })
+    }
+}
+
+impl DoubleEndedIterator for KvStoreScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let now = record::now_millis();
+        self.read(|cursor, key_dir| loop {
+            let (key, key_dir_value) = cursor.next_back(key_dir)?;
+            if key_dir_value.is_live(now) {
+                return Some((key.clone(), *key_dir_value));
+            }
+        })
+    }
+}
+
+impl Iterator for KvStoreKeys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let key_dir = self.key_dir.read().unwrap();
+        let now = record::now_millis();
+        loop {
+            let (key, key_dir_value) = self.cursor.next(&key_dir)?;
+            if key_dir_value.is_live(now) {
+                return Some(key.clone());
+            }
+        }
+    }
+}
+
+impl DoubleEndedIterator for KvStoreKeys {
+    fn next_back(&mut self) -> Option<Vec<u8>> {
+        let key_dir = self.key_dir.read().unwrap();
+        let now = record::now_millis();
+        loop {
+            let (key, key_dir_value) = self.cursor.next_back(&key_dir)?;
+            if key_dir_value.is_live(now) {
+                return Some(key.clone());
+            }
+        }
+    }
+}
+
+impl Worker {
+    fn spawn(job: impl FnOnce(Receiver<()>) + Send + 'static) -> Worker {
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || job(receiver));
+
+        Worker {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    fn notify(&self) {
+        if let Some(sender) = &self.sender {
+            // The thread only goes away when the handle is dropped
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the channel tells the thread to exit
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Sync the active file every `interval` while there are unsynced writes, and
+// one last time when the store is dropped
+fn run_flusher(writer: &Mutex<KvStoreWriter>, interval: Duration, receiver: Receiver<()>) {
+    loop {
+        let closed = match receiver.recv_timeout(interval) {
+            Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => true,
+        };
+
+        let mut writer = writer.lock().unwrap();
+        if writer.dirty {
+            if let Err(e) = writer.sync() {
+                log::error!("Background sync failed: {}", e);
+            }
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+impl Compactor {
+    fn run(self, receiver: Receiver<()>) {
+        while receiver.recv().is_ok() {
+            // Writes keep asking for a compaction until the active file is
+            // rolled over; one run covers all of those requests
+            while receiver.try_recv().is_ok() {}
+
+            if let Err(e) = self.compact() {
+                log::error!("Background compaction failed: {}", e);
+            }
+        }
+    }
+
+    // Merge every immutable data file into a single compact file.
+    //
+    // 1. Under the writer lock, roll the writer over to a new active file.
+    //    Every file older than it is now immutable.
+    // 2. Without holding any lock, copy the live records of those files into
+    //    compact_file, writing a hint alongside, while writes go on to the
+    //    new active file and reads keep using the old files.
+    // 3. Swap compact_file in: point every key_dir entry that was not
+    //    overwritten or removed in the meantime at its copy, then retire the
+    //    old files.
+    fn compact(&self) -> Result<()> {
+        // 1. Create the new writer to handle future writes
+        let compact_file_id = {
+            let mut writer = self.writer.lock().unwrap();
+            let compact_file_id = writer.file_id + 1;
+            let new_writer_id = writer.file_id + 2;
+
+            writer.roll_over(new_writer_id)?;
+            // All the garbage so far is in the files about to be merged, and
+            // overwriting their records from now on makes no more of it: the
+            // copies made of those records are counted once they are swapped in
+            writer.uncompacted = 0;
+            writer.retired_below = compact_file_id;
+            compact_file_id
+        };
+
+        // 2. Take a copy of the key_dir entries that live in immutable files,
+        // so the key_dir lock is not held while copying. Keys that expired are
+        // not copied at all.
+        let now = record::now_millis();
+        let (live, expired): (Vec<(Vec<u8>, KeyDirValue)>, Vec<_>) = self
+            .key_dir
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, record)| record.file_id < compact_file_id)
+            .map(|(key, record)| (key.clone(), *record))
+            .partition(|(_, record)| record.is_live(now));
+
+        // Copy the records from the old reader files to compact_file. Records
+        // are copied verbatim, so their checksums and timestamps are preserved.
+        // The file only gets its real name once it is complete, so a crash
+        // halfway through leaves nothing but a temporary file behind.
+        let compact_path = log_path(&self.dir, compact_file_id);
+        let tmp_path = compact_path.with_extension("log.tmp");
+        let mut compact_file = BufWriter::new(File::create(&tmp_path)?);
+        let mut hint = HintWriter::default();
+
+        let mut start_index = 0; // Used to keep track of the start_position for each record
+                                 // copied into the compact_file
+        let mut moved = Vec::with_capacity(live.len());
+        let mut buf = vec![];
+        for (key, record) in &live {
+            let reader = self.readers.read().unwrap()[&record.file_id].clone();
+            buf.resize(record.record_size, 0);
+            read_at(&reader, &mut buf, record.start_index)?;
+            compact_file.write_all(&buf)?;
+
+            hint.add(
+                record.timestamp,
+                key,
+                record.record_size,
+                start_index,
+                record.expires_at,
+            );
+            moved.push(start_index);
+
+            start_index += record.record_size as u64;
+        }
+        compact_file.flush()?;
+        compact_file.get_ref().sync_data()?;
+        fs::rename(&tmp_path, &compact_path)?;
+        hint.finish(&self.dir, compact_file_id, start_index)?;
+        // The compacted file must be there for good before the files it
+        // replaces go away
+        sync_dir(&self.dir)?;
+
+        // 3. Make all future reads to compact_file: register its reader first,
+        // then update the key_dir_values that still point at the old copy
+        self.readers
+            .write()
+            .unwrap()
+            .insert(compact_file_id, Arc::new(File::open(&compact_path)?));
+
+        let mut stale = 0; // copies of records that were overwritten during the merge
+        {
+            let mut key_dir = self.key_dir.write().unwrap();
+            for ((key, old), start_index) in live.iter().zip(moved) {
+                match key_dir.get_mut(key) {
+                    Some(record)
+                        if record.file_id == old.file_id
+                            && record.start_index == old.start_index =>
+                    {
+                        record.file_id = compact_file_id;
+                        record.start_index = start_index;
+                    }
+                    _ => stale += old.record_size,
+                }
+            }
+            // Expired keys are dropped unless they were set again meanwhile
+            for (key, old) in &expired {
+                if key_dir.get(key).is_some_and(|record| {
+                    record.file_id == old.file_id && record.start_index == old.start_index
+                }) {
+                    key_dir.remove(key);
+                }
+            }
+        }
+        self.writer.lock().unwrap().uncompacted += stale;
+
+        // Delete and remove from readers all files with file_id less than compact_file_id.
+        // Reads that already picked up one of their handles can still finish.
+        //
+        // Files go oldest first: should a crash interrupt this, the files left
+        // over are the newest ones, whose tombstones still cover what they
+        // removed. Deleting a newer file first could bring back a key that it
+        // removed. A hint goes before its data file, so none is left without.
+        let mut removed_size = 0;
+        {
+            let mut readers = self.readers.write().unwrap();
+            let mut remove_file_ids: Vec<_> = readers
+                .keys()
+                .filter(|&&file_id| file_id < compact_file_id)
+                .cloned()
+                .collect();
+            remove_file_ids.sort_unstable();
+
+            for file_id in remove_file_ids {
+                if let Some(reader) = readers.remove(&file_id) {
+                    removed_size += reader.metadata()?.len();
+                }
+                remove_if_exists(&hint::hint_path(&self.dir, file_id))?;
+                if self.archive {
+                    let archive_dir = self.dir.join(ARCHIVE_DIR);
+                    fs::create_dir_all(&archive_dir)?;
+                    fs::rename(
+                        log_path(&self.dir, file_id),
+                        log_path(&archive_dir, file_id),
+                    )?;
+                } else {
+                    fs::remove_file(log_path(&self.dir, file_id))?;
+                }
+            }
+        }
+        sync_dir(&self.dir)?;
+        let archive_dir = self.dir.join(ARCHIVE_DIR);
+        if self.archive && archive_dir.exists() {
+            sync_dir(&archive_dir)?;
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.disk_size = (writer.disk_size + start_index).saturating_sub(removed_size);
+
+        Ok(())
+    }
+}
+
+impl KvStoreWriter {
+    // Make `file_id` the active data file and register it with the readers.
+    // The current one becomes immutable, so whatever the sync policy still
+    // owes it is synced now.
+    fn roll_over(&mut self, file_id: usize) -> Result<()> {
+        if self.sync_policy == SyncPolicy::Never {
+            self.writer.flush()?;
+        } else {
+            self.sync()?;
+        }
+
+        let file = open_log(&self.dir, file_id)?;
+        self.write_index = file.metadata()?.len();
+        self.disk_size += self.write_index;
+        self.writer = BufWriter::new(file);
+        self.file_id = file_id;
+
+        self.readers
+            .write()
+            .unwrap()
+            .insert(file_id, Arc::new(File::open(log_path(&self.dir, file_id))?));
+
+        Ok(())
+    }
+
+    // Append the record to the active data file and return its (start_index, record_size)
+    fn append(&mut self, record: &Record) -> Result<(u64, usize)> {
+        if !record.fits() {
+            return Err(too_large(&record.key));
+        }
+        let buf = record.encode();
+        Ok((self.write(&buf)?, buf.len()))
+    }
+
+    // Append the records framed as one batch and return the start_index of
+    // the frame. The first record follows `HEADER_SIZE` bytes after it.
+    fn append_batch(&mut self, records: &[Record]) -> Result<u64> {
+        if !Entry::batch_fits(records) {
+            return Err(match records.iter().find(|record| !record.fits()) {
+                Some(record) => too_large(&record.key),
+                None => KvError::TooLarge(format!("batch of {} records", records.len())),
+            });
+        }
+        self.write(&Entry::encode_batch(records))
+    }
+
+    // Write one entry to the active data file and return its start_index.
+    //
+    // Once the active file has grown to `max_file_size` it is rotated out first,
+    // so a file goes past the limit by at most one entry.
+    //
+    // If the write fails, e.g. because the disk is full, whatever part of the
+    // entry reached the buffer or the file is discarded, so the next entry
+    // still starts at `write_index`. Should that fail too, the writer refuses
+    // any further write until the store is reopened, which truncates the torn
+    // entry on replay.
+    fn write(&mut self, buf: &[u8]) -> Result<u64> {
+        if self.broken {
+            return Err(io::Error::other(
+                "an earlier write failed and could not be undone; reopen the store",
+            )
+            .into());
+        }
+        if self.write_index > 0 && self.write_index >= self.max_file_size {
+            self.roll_over(self.file_id + 1)?;
+        }
+
+        let start_index = self.write_index;
+
+        // Flush so that the readers of the active file can see the record
+        let written = self
+            .writer
+            .write_all(buf)
+            .and_then(|()| self.writer.flush());
+        if let Err(e) = written {
+            if let Err(discard_error) = self.discard_partial_write() {
+                log::error!("Failed to undo a failed write: {}", discard_error);
+                self.broken = true;
+            }
+            return Err(e.into());
+        }
+        self.write_index += buf.len() as u64;
+        self.disk_size += buf.len() as u64;
+
+        if self.sync_policy == SyncPolicy::Always {
+            self.writer.get_ref().sync_data()?;
+        } else {
+            self.dirty = true;
+        }
+
+        Ok(start_index)
+    }
+
+    // Throw away what is buffered and cut the active file back to
+    // `write_index`. The buffer is dropped without being flushed.
+    fn discard_partial_write(&mut self) -> io::Result<()> {
+        let file = self.writer.get_ref().try_clone()?;
+        let (file, _) = mem::replace(&mut self.writer, BufWriter::new(file)).into_parts();
+        file.set_len(self.write_index)
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// Apply a record found at `start_index` of `file_id` to the key_dir and
+// return how many bytes of the data files it turned into garbage, see
+// `garbage` for `retired_below`
+fn apply_record(
+    key_dir: &mut BTreeMap<Vec<u8>, KeyDirValue>,
+    file_id: usize,
+    start_index: u64,
+    seq: u64,
+    record: Record,
+    retired_below: usize,
+) -> usize {
+    let record_size = record.encoded_len();
+    match record.value {
+        Some(_) => {
+            let key_dir_value = KeyDirValue {
+                file_id,
+                start_index,
+                record_size,
+                timestamp: record.timestamp,
+                seq,
+                expires_at: record.expires_at,
+            };
+            insert_key_dir_value(key_dir, record.key, key_dir_value, retired_below)
+        }
+        None => key_dir.remove(&record.key).map_or(0, |previous| {
+            garbage(Some(previous), retired_below) + record_size
+        }),
+    }
+}
+
+// Insert a value read back from the data files into the key_dir and return
+// how many bytes it turned into garbage. A value that already expired is
+// garbage itself and only removes what it overwrote, like a tombstone.
+fn insert_key_dir_value(
+    key_dir: &mut BTreeMap<Vec<u8>, KeyDirValue>,
+    key: Vec<u8>,
+    key_dir_value: KeyDirValue,
+    retired_below: usize,
+) -> usize {
+    if key_dir_value.is_live(record::now_millis()) {
+        garbage(key_dir.insert(key, key_dir_value), retired_below)
+    } else {
+        garbage(key_dir.remove(&key), retired_below) + key_dir_value.record_size
+    }
+}
+
+// How many bytes replacing `previous` turns into garbage. A record in a file
+// below `retired_below` is in one a compaction is merging away: the file goes
+// as a whole, and the compaction counts the copy it made instead.
+fn garbage(previous: Option<KeyDirValue>, retired_below: usize) -> usize {
+    previous
+        .filter(|previous| previous.file_id >= retired_below)
+        .map_or(0, |previous| previous.record_size)
+}
+
+// Read the record of `key` back and verify its checksum
+fn read_value(key: &[u8], key_dir_value: &KeyDirValue, file: &File) -> Result<Vec<u8>> {
+    let file_id = key_dir_value.file_id;
+    let start_index = key_dir_value.start_index;
+    let mut buf = vec![0; key_dir_value.record_size];
+    read_at(file, &mut buf, start_index)?;
+
+    let record = Record::decode(&buf).map_err(|_| KvError::Corruption {
+        file_id,
+        offset: start_index,
+    })?;
+
+    match record.value {
+        Some(value) if record.key == key => Ok(value),
+        // the key_dir never points at a tombstone or at another key's record
+        _ => Err(KvError::Corruption {
+            file_id,
+            offset: start_index,
+        }),
+    }
+}
+
+// Read exactly `buf.len()` bytes at `offset` without moving any shared cursor,
+// which is what lets concurrent readers share one file handle
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn log_path(dir: &Path, file_id: usize) -> PathBuf {
+    dir.join(format!("{}.log", file_id))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+// Whether what follows `index` in `file` is a torn tail, see
+// `record::is_torn_tail`
+fn is_torn_tail(file: &File, index: u64, data_len: u64) -> io::Result<bool> {
+    record::is_torn_tail(data_len - index, |buf, offset| {
+        read_at(file, buf, index + offset)
+    })
+}
+
+fn truncate_torn_tail(
+    dir: &Path,
+    file_id: usize,
+    len: u64,
+    discarded: u64,
+    read_only: bool,
+) -> io::Result<()> {
+    if read_only {
+        log::warn!(
+            "Ignoring {} bytes of an incomplete or damaged record at the end of `{}.log`",
+            discarded,
+            file_id
+        );
+        return Ok(());
+    }
+
+    log::warn!(
+        "Discarding {} bytes of an incomplete or damaged record at the end of `{}.log`",
+        discarded,
+        file_id
+    );
+    let file = OpenOptions::new()
+        .write(true)
+        .open(log_path(dir, file_id))?;
+    file.set_len(len)?;
+    file.sync_all()
+}
+
+fn too_large(key: &[u8]) -> KvError {
+    KvError::TooLarge(format!("key `{}`", String::from_utf8_lossy(key)))
+}
+
+fn record_with_ttl(key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Record {
+    let expires_at =
+        record::now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX));
+    Record::set_with_expiry(key, value, expires_at)
+}
+
+// Create `dir` for a copy of a store, making sure it holds nothing yet
+fn create_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("directory `{}` is not empty", dir.display()),
+        )));
+    }
+    Ok(())
+}
+
+// Copy the first `len` bytes of `file` to a new file at `dest_path`
+fn copy_file(file: &File, dest_path: &Path, len: u64) -> io::Result<()> {
+    let mut dest = File::create(dest_path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = &mut buf[..(len - offset).min(64 * 1024) as usize];
+        read_at(file, chunk, offset)?;
+        dest.write_all(chunk)?;
+        offset += chunk.len() as u64;
+    }
+    dest.sync_all()
+}
+
+// Make the creations, renames and removals of files in `dir` durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+fn open_log(dir: &Path, file_id: usize) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(log_path(dir, file_id))
+}
+
+// A record cut short or failing its checksum at the very end of the newest
+// file is what a crash in the middle of a write leaves behind, so it is cut
+// off (or, read-only, just skipped) and the rest of the store opens normally.
+// Any other damaged record, including one followed by intact records, fails
+// the open with `KvError::Corruption`.
+fn replay_log(
+    dir: &Path,
+    read_only: bool,
+    file_id: &mut usize,
+    readers: &mut HashMap<usize, Arc<File>>,
+    key_dir: &mut BTreeMap<Vec<u8>, KeyDirValue>,
+    uncompacted: &mut usize,
+) -> Result<()> {
+    // need to find all files in the dir.
+    // the file with largest file_id will be the writer, and the rest
+    // of them will be put into the readers
+    let mut file_ids = vec![];
+
+    // Get sorted file name vector, cleaning up the temporary files of a
+    // compaction that was interrupted by a crash
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if !read_only
+            && file_name
+                .to_str()
+                .is_some_and(|name| name.ends_with(".tmp"))
+        {
+            remove_if_exists(&entry.path())?;
+            continue;
+        }
+        let id = file_name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|id| id.parse::<usize>().ok());
+        if let Some(id) = id {
+            file_ids.push(id);
+        }
+    }
+
+    if file_ids.is_empty() {
+        // If there is no log in the directory,
+        // It means this is the first start. So you can just create a single writer file
+        *file_id = 0;
+        return Ok(());
+    }
+
+    file_ids.sort_unstable();
+
+    // Every time a kvstore is reopen, create a new writer file
+    let newest = file_ids[file_ids.len() - 1];
+    *file_id = newest + 1;
+
+    for id in file_ids {
+        let mut reader = BufReader::new(File::open(log_path(dir, id))?);
+
+        // Files produced by compaction come with a hint, which is enough to
+        // rebuild their part of the key_dir without reading any value
+        let data_len = reader.get_ref().metadata()?.len();
+        if let Some(entries) = hint::read_hint(dir, id, data_len)? {
+            for entry in entries {
+                let key_dir_value = KeyDirValue {
+                    file_id: id,
+                    start_index: entry.start_index,
+                    record_size: entry.record_size,
+                    timestamp: entry.timestamp,
+                    seq: 0,
+                    expires_at: entry.expires_at,
+                };
+                *uncompacted += insert_key_dir_value(key_dir, entry.key, key_dir_value, 0);
+            }
+
+            readers.insert(id, Arc::new(reader.into_inner()));
+            continue;
+        }
+
+        let mut index = 0;
+
+        // Loop each reader file, verifying the checksum of every record
+        loop {
+            let entry = match Entry::read_from(&mut reader) {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(e)
+                    if e.kind() == io::ErrorKind::InvalidData
+                        || e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    if id == newest && is_torn_tail(reader.get_ref(), index, data_len)? {
+                        truncate_torn_tail(dir, id, index, data_len - index, read_only)?;
+                        break;
+                    }
+                    return Err(KvError::Corruption {
+                        file_id: id,
+                        offset: index,
+                    });
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // The records of a batch follow its frame header
+            *uncompacted += entry.overhead();
+            index += entry.overhead() as u64;
+            let records = match entry {
+                Entry::Record(record) => vec![record],
+                Entry::Batch(records) => records,
+            };
+
+            for record in records {
+                let record_size = record.encoded_len();
+                *uncompacted += apply_record(key_dir, id, index, 0, record, 0);
+                index += record_size as u64;
+            }
+        }
+
+        readers.insert(id, Arc::new(reader.into_inner()));
+    }
+
+    Ok(())
+}
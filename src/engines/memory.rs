@@ -0,0 +1,84 @@
+use super::{Cursor, KvsEngine};
+use crate::{KvError, Result, WriteBatch};
+use std::{
+    collections::BTreeMap,
+    ops::RangeBounds,
+    sync::{Arc, RwLock},
+};
+
+// A purely in-memory engine. Nothing is persisted, which makes it handy for
+// tests of code written against `KvsEngine`.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    map: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+// Scan over a `MemoryStore`, see `KvsEngine::Scan`
+pub struct MemoryScan {
+    map: Arc<RwLock<BTreeMap<Vec<u8>, Vec<u8>>>>,
+    cursor: Cursor,
+}
+
+impl KvsEngine for MemoryStore {
+    type Scan = MemoryScan;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.map.read().unwrap().get(key).cloned())
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        match self.map.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(KvError::KeyNotFound(
+                String::from_utf8_lossy(key).into_owned(),
+            )),
+        }
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for (key, value) in batch.into_ops() {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> MemoryScan {
+        MemoryScan {
+            map: self.map.clone(),
+            cursor: Cursor::new(range),
+        }
+    }
+}
+
+impl Iterator for MemoryScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map = self.map.read().unwrap();
+        let (key, value) = self.cursor.next(&map)?;
+        Some(Ok((key.clone(), value.clone())))
+    }
+}
+
+impl DoubleEndedIterator for MemoryScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let map = self.map.read().unwrap();
+        let (key, value) = self.cursor.next_back(&map)?;
+        Some(Ok((key.clone(), value.clone())))
+    }
+}
@@ -0,0 +1,141 @@
+use crate::{Result, WriteBatch};
+use std::{collections::BTreeMap, ops::Bound, ops::RangeBounds};
+
+mod async_kvs;
+mod kvs;
+mod memory;
+mod transaction;
+
+pub use self::async_kvs::AsyncKvStore;
+pub use self::kvs::{KeyMetadata, KvStore, KvStoreKeys, KvStoreScan, KvStoreStats};
+pub use self::memory::{MemoryScan, MemoryStore};
+pub use self::transaction::Transaction;
+
+// The interface every storage engine exposes. Application code (and the `kvs`
+// binary) is written against this trait so the backing engine can be chosen
+// at runtime.
+//
+// Keys and values are raw bytes; the string methods are convenience wrappers
+// for the common case of UTF-8 text.
+//
+// Engines are cheap handles: cloning one gives another handle to the same
+// underlying store, and every method takes `&self` so clones can be used from
+// several threads at once.
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    // Iterator over `(key, value)` pairs in key order, returned by the scans.
+    // It is double-ended, so `.rev()` walks the keys from the end, and lazy,
+    // so `.take(limit)` only ever reads `limit` values.
+    type Scan: DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>>;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    // Fails with `KvError::KeyNotFound` if the key does not exist
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
+
+    // Apply every write in the batch, in order, as one atomic step
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    // All pairs whose key falls in `range`, e.g. `store.range("a".."c")`.
+    // The scan is not a snapshot: it sees writes made while it is in progress.
+    fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Self::Scan;
+
+    // All pairs whose key starts with `prefix`
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Self::Scan {
+        self.range(prefix_range(prefix.as_ref()))
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+// The range of keys starting with `prefix`: from the prefix itself up to,
+// but excluding, the first key that is greater than every key with it
+fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    // The prefix is empty or all 0xff bytes
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+// The part of a range that has not been handed out yet by either end of a
+// scan. Every step looks the next key up afresh, so the scan holds no lock
+// in between and never blocks writers.
+struct Cursor {
+    front: Bound<Vec<u8>>,
+    back: Bound<Vec<u8>>,
+}
+
+impl Cursor {
+    fn new<K: AsRef<[u8]>>(range: impl RangeBounds<K>) -> Cursor {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Cursor {
+            front: owned(range.start_bound()),
+            back: owned(range.end_bound()),
+        }
+    }
+
+    fn next<'a, V>(&mut self, map: &'a BTreeMap<Vec<u8>, V>) -> Option<(&'a Vec<u8>, &'a V)> {
+        let (key, value) = self.range(map)?.next()?;
+        self.front = Bound::Excluded(key.clone());
+        Some((key, value))
+    }
+
+    fn next_back<'a, V>(&mut self, map: &'a BTreeMap<Vec<u8>, V>) -> Option<(&'a Vec<u8>, &'a V)> {
+        let (key, value) = self.range(map)?.next_back()?;
+        self.back = Bound::Excluded(key.clone());
+        Some((key, value))
+    }
+
+    // `BTreeMap::range` panics on an inverted or empty excluded range, which
+    // is where the two ends meet once the scan is exhausted
+    fn range<'a, V>(
+        &self,
+        map: &'a BTreeMap<Vec<u8>, V>,
+    ) -> Option<std::collections::btree_map::Range<'a, Vec<u8>, V>> {
+        let front = match &self.front {
+            Bound::Included(key) => Bound::Included(key.as_slice()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let back = match &self.back {
+            Bound::Included(key) => Bound::Included(key.as_slice()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_slice()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        match (front, back) {
+            (Bound::Included(start), Bound::Included(end)) if start > end => return None,
+            (Bound::Included(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end))
+            | (Bound::Excluded(start), Bound::Excluded(end))
+                if start >= end =>
+            {
+                return None
+            }
+            _ => {}
+        }
+        Some(map.range::<[u8], _>((front, back)))
+    }
+}
@@ -0,0 +1,80 @@
+// An optimistic transaction on a `KvStore`, started with `KvStore::transaction`.
+//
+// Nothing is locked while it runs. Every key it reads is remembered together
+// with the sequence number of the write that produced its value, and writes
+// are buffered until `commit`. The commit then applies them as one batch, but
+// only if none of the keys read has been written since, failing with
+// `KvError::Conflict` otherwise. A conflicting transaction can simply be run
+// again from the start.
+
+use super::KvStore;
+use crate::{Result, WriteBatch};
+use std::collections::{BTreeMap, HashMap};
+
+pub struct Transaction {
+    store: KvStore,
+    reads: HashMap<Vec<u8>, Option<u64>>, // the sequence number each key was read at
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>, // None for a remove
+}
+
+impl Transaction {
+    pub(super) fn new(store: KvStore) -> Transaction {
+        Transaction {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    // Reads see the transaction's own writes
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+
+        let found = self.store.get_with_seq(key)?;
+        let seq = found.as_ref().map(|(_, seq)| *seq);
+        // Keep the first version read, so a key changing in between two reads
+        // of it is caught too
+        self.reads.entry(key.to_vec()).or_insert(seq);
+        Ok(found.map(|(value, _)| value))
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes());
+    }
+
+    // Unlike `KvsEngine::remove`, removing a key that does not exist is not
+    // an error
+    pub fn remove_bytes(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.remove_bytes(key.into_bytes());
+    }
+
+    // Apply the writes if none of the keys read has changed since, or fail
+    // with `KvError::Conflict` and apply nothing
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.store.commit(&self.reads, batch)
+    }
+}
@@ -0,0 +1,28 @@
+use std::io;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, KvError>;
+
+#[derive(Error, Debug)]
+pub enum KvError {
+    #[error("Encountered IO error `{0}`")]
+    Io(#[from] io::Error),
+    #[error("Key to be removed: `{0}` is not found")]
+    KeyNotFound(String),
+    #[error("Serde parsing error `{0}`")]
+    SerdeError(#[from] serde_json::Error),
+    #[error("Corrupted record in `{file_id}.log` at offset {offset}")]
+    Corruption { file_id: usize, offset: u64 },
+    #[error("Stored value is not valid UTF-8 `{0}`")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("Store was opened read-only")]
+    ReadOnly,
+    #[error("Store directory is locked by {}", crate::lock::describe_holder(.0))]
+    Locked(Option<u32>), // the PID of the writer holding the lock, if any
+    #[error("Transaction conflict: `{0}` was changed after it was read")]
+    Conflict(String),
+    #[error("Too large to be stored: {0}")]
+    TooLarge(String), // what was too large, e.g. "key `k`"
+    #[error("{0}")]
+    Server(String), // an error the server ran into, as it displayed it
+}
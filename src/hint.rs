@@ -0,0 +1,144 @@
+// A hint file `N.hint` sits next to an immutable data file `N.log` written by
+// compaction and lists, for every record in it, what the key_dir needs:
+//
+// | timestamp (u64) | key_size (u32) | record_size (u32) | start_index (u64) | key |
+//
+// As in the data file, an entry for a record with a time-to-live has the
+// `EXPIRES` bit set in its key_size and its expiry time (u64) before the key.
+//
+// The entries are followed by a footer holding the length of `N.log` at the
+// time the hint was written (u64) and a crc (u32) over everything before it.
+// `open` reads the hint instead of the data file, so values never have to be
+// touched; a hint that is missing, truncated or does not match its data file
+// is ignored and the data file is replayed in full instead.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const ENTRY_HEADER_SIZE: usize = 8 + 4 + 4 + 8;
+const FOOTER_SIZE: usize = 8 + 4;
+const EXPIRES: u32 = 1 << 31;
+
+#[derive(Debug)]
+pub struct HintEntry {
+    pub timestamp: u64,
+    pub key: Vec<u8>,
+    pub record_size: usize,
+    pub start_index: u64,
+    pub expires_at: Option<u64>,
+}
+
+// Accumulates hint entries while a data file is being written
+#[derive(Default)]
+pub struct HintWriter {
+    buf: Vec<u8>,
+}
+
+impl HintWriter {
+    pub fn add(
+        &mut self,
+        timestamp: u64,
+        key: &[u8],
+        record_size: usize,
+        start_index: u64,
+        expires_at: Option<u64>,
+    ) {
+        // Records never exceed `record::MAX_RECORD_SIZE`, so the size fits
+        let key_size = key.len() as u32 | expires_at.map_or(0, |_| EXPIRES);
+        self.buf.extend_from_slice(&timestamp.to_le_bytes());
+        self.buf.extend_from_slice(&key_size.to_le_bytes());
+        self.buf
+            .extend_from_slice(&(record_size as u32).to_le_bytes());
+        self.buf.extend_from_slice(&start_index.to_le_bytes());
+        if let Some(expires_at) = expires_at {
+            self.buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        self.buf.extend_from_slice(key);
+    }
+
+    // Seal the hint with the final length of its data file and write it to disk
+    pub fn finish(mut self, dir: &Path, file_id: usize, data_len: u64) -> io::Result<()> {
+        self.buf.extend_from_slice(&data_len.to_le_bytes());
+        let crc = crc32fast::hash(&self.buf);
+        self.buf.extend_from_slice(&crc.to_le_bytes());
+
+        // Write to a temporary file first so a crash never leaves a half
+        // written hint under the real name
+        let tmp_path = hint_path(dir, file_id).with_extension("hint.tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(&self.buf)?;
+        file.sync_data()?;
+        fs::rename(tmp_path, hint_path(dir, file_id))
+    }
+}
+
+// Load the hint of `file_id` if it exists and is valid for a data file of
+// `data_len` bytes. Returns `Ok(None)` when the data file must be replayed.
+pub fn read_hint(dir: &Path, file_id: usize, data_len: u64) -> io::Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(hint_path(dir, file_id)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if buf.len() < FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    // 1. Validate the footer
+    let (body, footer) = buf.split_at(buf.len() - 4);
+    let crc = u32::from_le_bytes(footer.try_into().unwrap());
+    if crc32fast::hash(body) != crc {
+        return Ok(None);
+    }
+    let (entries, len) = body.split_at(body.len() - 8);
+    if u64::from_le_bytes(len.try_into().unwrap()) != data_len {
+        return Ok(None);
+    }
+
+    // 2. Parse the entries, making sure each one points inside the data file
+    let mut result = vec![];
+    let mut rest = entries;
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_SIZE {
+            return Ok(None);
+        }
+        let timestamp = u64::from_le_bytes(rest[0..8].try_into().unwrap());
+        let key_size = u32::from_le_bytes(rest[8..12].try_into().unwrap());
+        let record_size = u32::from_le_bytes(rest[12..16].try_into().unwrap()) as usize;
+        let start_index = u64::from_le_bytes(rest[16..24].try_into().unwrap());
+        rest = &rest[ENTRY_HEADER_SIZE..];
+
+        let mut expires_at = None;
+        if key_size & EXPIRES != 0 {
+            if rest.len() < 8 {
+                return Ok(None);
+            }
+            expires_at = Some(u64::from_le_bytes(rest[..8].try_into().unwrap()));
+            rest = &rest[8..];
+        }
+        let key_size = (key_size & !EXPIRES) as usize;
+
+        if rest.len() < key_size || start_index + record_size as u64 > data_len {
+            return Ok(None);
+        }
+        let (key, tail) = rest.split_at(key_size);
+        rest = tail;
+
+        result.push(HintEntry {
+            timestamp,
+            key: key.to_vec(),
+            record_size,
+            start_index,
+            expires_at,
+        });
+    }
+
+    Ok(Some(result))
+}
+
+pub fn hint_path(dir: &Path, file_id: usize) -> PathBuf {
+    dir.join(format!("{}.hint", file_id))
+}
@@ -0,0 +1,397 @@
+// An HTTP/1.1 server exposing a `KvStore` as a small JSON API, for clients
+// that only speak HTTP:
+//
+// GET    /keys/{key}        200 {"key": .., "value": ..}, 404 if it has no value
+// PUT    /keys/{key}        set it to the `value` of a {"value": ..} body, 204
+// DELETE /keys/{key}        204, 404 if it has no value
+// GET    /keys?prefix={p}   200 [{"key": .., "value": ..}, ..] in key order
+// GET    /stats             200 {"keys": .., "data_files": .., ..}
+//
+// Keys in the path and the prefix are percent-decoded. Errors come back as
+// {"error": ..} with 404 for `KvError::KeyNotFound` and 500 for the store's
+// other errors, like `Io` or `SerdeError`. Only bodies with a Content-Length
+// are accepted.
+
+use crate::{
+    server::serve_connections, KvError, KvStore, KvsEngine, NaiveThreadPool, Result, ThreadPool,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+const MAX_LINE_LEN: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+pub struct HttpServer<P: ThreadPool = NaiveThreadPool> {
+    store: KvStore,
+    pool: P,
+}
+
+impl HttpServer {
+    pub fn new(store: KvStore) -> HttpServer {
+        HttpServer::with_pool(store, NaiveThreadPool)
+    }
+}
+
+// Serve each connection as one job of `pool`, see `KvsServer::with_pool` and
+// `server::serve_connections`
+impl<P: ThreadPool> HttpServer<P> {
+    pub fn with_pool(store: KvStore, pool: P) -> HttpServer<P> {
+        HttpServer { store, pool }
+    }
+
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let store = self.store;
+        serve_connections(listener, &self.pool, move |stream| {
+            handle_connection(&store, stream)
+        })
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    body: Option<Value>, // None for 204 No Content
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn json(status: u16, body: Value) -> Response {
+        Response {
+            status,
+            body: Some(body),
+            allow: None,
+        }
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Response {
+        Response::json(status, json!({ "error": message.into() }))
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Response {
+        Response {
+            allow: Some(allow),
+            ..Response::error(405, "method not allowed")
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, keep_alive: bool) -> io::Result<()> {
+        let body = match &self.body {
+            Some(body) => serde_json::to_vec(body)?,
+            None => vec![],
+        };
+
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if self.body.is_some() {
+            write!(writer, "Content-Type: application/json\r\n")?;
+        }
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(&body)?;
+        writer.flush()
+    }
+}
+
+impl From<KvError> for Response {
+    fn from(e: KvError) -> Response {
+        let status = match e {
+            KvError::KeyNotFound(_) => 404,
+            _ => 500,
+        };
+        Response::error(status, e.to_string())
+    }
+}
+
+fn handle_connection(store: &KvStore, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            // Answer a malformed request, then give up on the connection,
+            // since where the next request starts is unknown
+            Err(ReadError::Rejected(status, message)) => {
+                return Response::error(status, message).write_to(&mut writer, false);
+            }
+            Err(ReadError::Io(e)) => return Err(e),
+        };
+
+        log::debug!("Received {} {}", request.method, request.target);
+        let response = route(store, &request);
+        response.write_to(&mut writer, request.keep_alive)?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+fn route(store: &KvStore, request: &Request) -> Response {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request.target.as_str(), None),
+    };
+
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = match percent_decode(key).map(String::from_utf8) {
+            Some(Ok(key)) => key,
+            _ => return Response::error(400, "key is not valid percent-encoded UTF-8"),
+        };
+        return match request.method.as_str() {
+            "GET" => get(store, key),
+            "PUT" => put(store, key, &request.body),
+            "DELETE" => store
+                .remove(key)
+                .map_or_else(Response::from, |_| Response::no_content()),
+            _ => Response::method_not_allowed("GET, PUT, DELETE"),
+        };
+    }
+
+    match (path, request.method.as_str()) {
+        ("/keys", "GET") => scan(store, query.unwrap_or("")),
+        ("/stats", "GET") => stats(store),
+        ("/keys", _) | ("/stats", _) => Response::method_not_allowed("GET"),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+fn get(store: &KvStore, key: String) -> Response {
+    match store.get(key.clone()) {
+        Ok(Some(value)) => Response::json(200, json!({ "key": key, "value": value })),
+        Ok(None) => KvError::KeyNotFound(key).into(),
+        Err(e) => e.into(),
+    }
+}
+
+fn put(store: &KvStore, key: String, body: &[u8]) -> Response {
+    #[derive(Deserialize)]
+    struct Body {
+        value: String,
+    }
+
+    // A body we cannot read is the client's fault, unlike the store's errors
+    let body: Body = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => return Response::error(400, format!("invalid body: {}", e)),
+    };
+    store
+        .set(key, body.value)
+        .map_or_else(Response::from, |_| Response::no_content())
+}
+
+fn scan(store: &KvStore, query: &str) -> Response {
+    let mut prefix = vec![];
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if name == "prefix" {
+            match percent_decode(&value.replace('+', " ")) {
+                Some(value) => prefix = value,
+                None => return Response::error(400, "prefix is not valid percent-encoding"),
+            }
+        }
+    }
+
+    let mut pairs = vec![];
+    for pair in store.scan_prefix(prefix) {
+        let pair = pair.and_then(|(key, value)| {
+            Ok(json!({
+                "key": String::from_utf8(key)?,
+                "value": String::from_utf8(value)?,
+            }))
+        });
+        match pair {
+            Ok(pair) => pairs.push(pair),
+            Err(e) => return e.into(),
+        }
+    }
+    Response::json(200, Value::Array(pairs))
+}
+
+fn stats(store: &KvStore) -> Response {
+    match store.stats() {
+        Ok(stats) => Response::json(
+            200,
+            json!({
+                "keys": stats.keys,
+                "data_files": stats.data_files,
+                "disk_size": stats.disk_size,
+                "uncompacted": stats.uncompacted,
+            }),
+        ),
+        Err(e) => e.into(),
+    }
+}
+
+enum ReadError {
+    Io(io::Error),
+    Rejected(u16, &'static str), // the status and message to answer it with
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+// Read the next request. Returns `Ok(None)` once the client closed the
+// connection.
+fn read_request<R: BufRead>(reader: &mut R) -> std::result::Result<Option<Request>, ReadError> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+            (method.to_owned(), target.to_owned(), version)
+        }
+        _ => return Err(bad_request("malformed request line")),
+    };
+    // HTTP/1.1 keeps the connection open by default, HTTP/1.0 closes it
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(bad_request("unsupported HTTP version")),
+    };
+
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        let line =
+            read_line(reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(too_large());
+        }
+
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| bad_request("malformed header"))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| bad_request("invalid Content-Length"))?;
+            }
+            "transfer-encoding" => {
+                return Err(bad_request("Transfer-Encoding is not supported"));
+            }
+            "connection" => {
+                if value.eq_ignore_ascii_case("close") {
+                    keep_alive = false;
+                } else if value.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(too_large());
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Some(Request {
+        method,
+        target,
+        body,
+        keep_alive,
+    }))
+}
+
+// Read a line without its CRLF
+fn read_line<R: BufRead>(reader: &mut R) -> std::result::Result<Option<String>, ReadError> {
+    let mut line = vec![];
+    reader
+        .take(MAX_LINE_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(too_large());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| bad_request("request is not valid UTF-8"))
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    Some(decoded)
+}
+
+fn bad_request(message: &'static str) -> ReadError {
+    ReadError::Rejected(400, message)
+}
+
+fn too_large() -> ReadError {
+    ReadError::Rejected(413, "request is too large")
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
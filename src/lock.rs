@@ -0,0 +1,78 @@
+// An advisory lock on the `LOCK` file of a store directory, held for as long
+// as the store is open. A writable store takes it exclusively and records its
+// PID in the file so that whoever is turned away can tell who has it; read-only
+// stores share it with each other but not with a writer.
+//
+// The lock belongs to the open file, so it is released when the file is
+// closed, including when the process dies without running any destructor.
+
+use crate::{KvError, Result};
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Write},
+    path::Path,
+    process,
+};
+
+pub struct DirLock {
+    file: File,
+    exclusive: bool,
+}
+
+impl DirLock {
+    pub fn acquire(dir: &Path, exclusive: bool) -> Result<DirLock> {
+        let path = dir.join("LOCK");
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+        {
+            Ok(file) => file,
+            // A reader may not be allowed to write to the directory
+            Err(_) if !exclusive => File::open(&path)?,
+            Err(e) => return Err(e.into()),
+        };
+
+        let locked = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match locked {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(KvError::Locked(pid.trim().parse().ok()));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        if exclusive {
+            file.set_len(0)?;
+            file.write_all(process::id().to_string().as_bytes())?;
+        }
+
+        Ok(DirLock { file, exclusive })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // Clear the PID so it does not outlive the lock
+        if self.exclusive {
+            let _ = self.file.set_len(0);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+// Only used to give `KvError::Locked` a readable message
+pub fn describe_holder(pid: &Option<u32>) -> String {
+    match pid {
+        Some(pid) => format!("process {}", pid),
+        None => "a read-only process".to_owned(),
+    }
+}
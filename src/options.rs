@@ -0,0 +1,105 @@
+// Settings accepted by `KvStore::open_with`. `KvStore::open` uses
+// `Options::default()`.
+
+use std::time::Duration;
+
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 24;
+
+// When writes are forced from the OS page cache to disk with `fsync`. Every
+// write is handed to the OS before `set`/`remove` return regardless, so this
+// only matters if the machine (rather than the process) goes down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    // Sync the active data file before every write returns
+    Always,
+    // Sync from a background thread at most this long after a write
+    Interval(Duration),
+    // Leave it to the OS (the default)
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(crate) compaction_threshold: u64,
+    pub(crate) compaction_ratio: f64,
+    pub(crate) read_only: bool,
+    pub(crate) create_if_missing: bool,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) max_file_size: u64,
+    pub(crate) archive: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: 0.0,
+            read_only: false,
+            create_if_missing: true,
+            sync_policy: SyncPolicy::Never,
+            max_file_size: u64::MAX,
+            archive: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    // Compaction is only considered once more than this many bytes of the
+    // data files are taken by overwritten or removed records
+    pub fn compaction_threshold(mut self, bytes: u64) -> Options {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    // ... and once those bytes make up at least this fraction (0.0 to 1.0)
+    // of all data files. The default of 0.0 only looks at the threshold.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Options {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    // Open the store without writing anything to its directory. `set` and
+    // `remove` fail with `KvError::ReadOnly` and no compaction ever runs.
+    pub fn read_only(mut self, read_only: bool) -> Options {
+        self.read_only = read_only;
+        self
+    }
+
+    // Create the directory if it does not exist yet (the default). Otherwise
+    // opening a missing directory fails.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Options {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Options {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    // Roll the active data file over to a new one once it reaches this size,
+    // making it immutable. Files are unbounded by default. The output of a
+    // compaction is always a single file, whatever its size.
+    pub fn max_file_size(mut self, bytes: u64) -> Options {
+        self.max_file_size = bytes;
+        self
+    }
+
+    // Move the data files superseded by a compaction to the `archive`
+    // subdirectory of the store instead of deleting them, so `KvStore::restore`
+    // can go back to any point in time since. Nothing is ever removed from the
+    // archive automatically.
+    pub fn archive(mut self, archive: bool) -> Options {
+        self.archive = archive;
+        self
+    }
+
+    pub(crate) fn should_compact(&self, uncompacted: u64, disk_size: u64) -> bool {
+        uncompacted > self.compaction_threshold
+            && uncompacted as f64 >= self.compaction_ratio * disk_size as f64
+    }
+}
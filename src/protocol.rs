@@ -0,0 +1,90 @@
+// Messages exchanged between `KvsClient` and `KvsServer`. Every message is a
+// frame holding one JSON document:
+//
+// | length (u32) | JSON |
+//
+// The length is little-endian and counts the JSON bytes only. A connection
+// carries any number of requests, each answered by one response before the
+// next request is read.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Frames larger than this are rejected rather than allocated
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Set { key: String, value: String },
+    Get { key: String },
+    Remove { key: String },
+    // Back the store up with `KvStore::checkpoint` into the directory `name`
+    // under the server's backup directory, see `server::backup_path`. The
+    // backup holds every write made before it started and none made after.
+    Backup { name: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok(Option<String>), // the value for a get, None otherwise
+    KeyNotFound(String),
+    Err(String), // any other error, as displayed by the server
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, message: &impl Serialize) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    writer.write_all(&(json.len() as u32).to_le_bytes())?;
+    writer.write_all(&json)?;
+    writer.flush()
+}
+
+// Returns `Ok(None)` if the peer closed the connection before a new frame
+pub fn read_frame<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut json = vec![0; frame_len(len)?];
+    reader.read_exact(&mut json)?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+// `write_frame` for async writers
+pub async fn write_frame_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &impl Serialize,
+) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    writer.write_all(&(json.len() as u32).to_le_bytes()).await?;
+    writer.write_all(&json).await?;
+    writer.flush().await
+}
+
+// `read_frame` for async readers
+pub async fn read_frame_async<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut json = vec![0; frame_len(len)?];
+    reader.read_exact(&mut json).await?;
+    Ok(Some(serde_json::from_slice(&json)?))
+}
+
+fn frame_len(len: [u8; 4]) -> io::Result<usize> {
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        ));
+    }
+    Ok(len)
+}
@@ -0,0 +1,440 @@
+// On-disk layout of a single entry in a `N.log` data file:
+//
+// | crc (u32) | timestamp (u64) | key_size (u32) | value_size (u32) | key | value |
+//
+// All integers are little-endian. The crc is computed over everything that
+// follows it (header fields, key and value), so a flipped bit anywhere in the
+// entry is caught when it is read back. A remove is written as a tombstone: a
+// record whose value_size is `TOMBSTONE` and which carries no value bytes.
+//
+// A record set with a time-to-live has the `EXPIRES` bit set in its key_size
+// and carries the time it expires at (u64, milliseconds since the unix epoch)
+// right after the header:
+//
+// | crc | timestamp | key_size + EXPIRES | value_size | expires_at (u64) | key | value |
+//
+// A write batch is framed as a single entry holding complete records:
+//
+// | crc (u32) | timestamp (u64) | BATCH (u32) | body_size (u32) | record | record | ... |
+//
+// The `BATCH` marker takes the place of the key_size and the crc covers the
+// whole frame, so a batch torn by a crash is dropped as a whole on replay.
+// Each record inside keeps its own header and crc, which lets the key_dir
+// point straight at it like at any other record.
+
+use std::{
+    io::{self, Read},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const HEADER_SIZE: usize = 4 + 8 + 4 + 4;
+
+const TOMBSTONE: u32 = u32::MAX;
+const BATCH: u32 = u32::MAX;
+const EXPIRES: u32 = 1 << 31;
+
+// The largest key and whole record that can be stored. A longer key would
+// set the `EXPIRES` bit, and one of `EXPIRES - 1` bytes with it set would
+// read as `BATCH`. A record must fit the u32 record_size of a hint entry,
+// which also keeps its value short of `TOMBSTONE` bytes.
+pub const MAX_KEY_SIZE: usize = (EXPIRES - 2) as usize;
+pub const MAX_RECORD_SIZE: usize = u32::MAX as usize;
+// The largest entry that is ever written: a batch frame with the largest body
+const MAX_ENTRY_SIZE: u64 = HEADER_SIZE as u64 + u32::MAX as u64;
+
+// How much of a damaged tail `is_torn_tail` reads at once
+const TAIL_CHUNK_SIZE: usize = 64 * 1024;
+
+// What is read from a data file: a record, or a batch of them
+#[derive(Debug)]
+pub enum Entry {
+    Record(Record),
+    Batch(Vec<Record>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: u64, // milliseconds since the unix epoch
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,  // None for a tombstone
+    pub expires_at: Option<u64>, // milliseconds since the unix epoch, None to never expire
+}
+
+impl Record {
+    pub fn set(key: Vec<u8>, value: Vec<u8>) -> Record {
+        Record {
+            timestamp: now_millis(),
+            key,
+            value: Some(value),
+            expires_at: None,
+        }
+    }
+
+    pub fn set_with_expiry(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Record {
+        Record {
+            expires_at: Some(expires_at),
+            ..Record::set(key, value)
+        }
+    }
+
+    pub fn tombstone(key: Vec<u8>) -> Record {
+        Record {
+            timestamp: now_millis(),
+            key,
+            value: None,
+            expires_at: None,
+        }
+    }
+
+    // Whether the key and the whole record are small enough to be encoded
+    pub fn fits(&self) -> bool {
+        self.key.len() <= MAX_KEY_SIZE && self.encoded_len() <= MAX_RECORD_SIZE
+    }
+
+    // Total number of bytes this record occupies on disk
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE
+            + self.expires_at.map_or(0, |_| 8)
+            + self.key.len()
+            + self.value.as_ref().map_or(0, |v| v.len())
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        let value_size = match &self.value {
+            Some(value) => value.len() as u32,
+            None => TOMBSTONE,
+        };
+
+        // Leave room for the crc, which is filled in once the rest is written
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        let key_size = key_size_field(self.key.len(), self.expires_at.is_some());
+        buf.extend_from_slice(&key_size.to_le_bytes());
+        buf.extend_from_slice(&value_size.to_le_bytes());
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.key);
+        if let Some(value) = &self.value {
+            buf.extend_from_slice(value);
+        }
+
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    // Decode a record that is known to occupy exactly `buf`, e.g. one located
+    // through the key_dir. Fails with `InvalidData` on a checksum mismatch.
+    pub fn decode(buf: &[u8]) -> io::Result<Record> {
+        if buf.len() < HEADER_SIZE {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = Header::parse(&buf[..HEADER_SIZE]);
+        if header.batch {
+            return Err(invalid_data("expected a record, found a batch"));
+        }
+        if buf.len() != HEADER_SIZE + header.body_len() {
+            return Err(invalid_data("record length does not match its header"));
+        }
+        header.finish(&buf[HEADER_SIZE..])
+    }
+}
+
+impl Entry {
+    // Whether `records` can be framed as one batch: each must fit, and so
+    // must all of them together in the frame's body_size
+    pub fn batch_fits(records: &[Record]) -> bool {
+        records.iter().all(Record::fits)
+            && records.iter().map(Record::encoded_len).sum::<usize>() <= u32::MAX as usize
+    }
+
+    // Frame `records` as one batch. The first record starts `HEADER_SIZE`
+    // bytes into the frame and the others follow it back to back.
+    pub fn encode_batch(records: &[Record]) -> Vec<u8> {
+        let body_size: usize = records.iter().map(Record::encoded_len).sum();
+        let mut buf = Vec::with_capacity(HEADER_SIZE + body_size);
+
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&now_millis().to_le_bytes());
+        buf.extend_from_slice(&BATCH.to_le_bytes());
+        buf.extend_from_slice(&(body_size as u32).to_le_bytes());
+        for record in records {
+            buf.extend_from_slice(&record.encode());
+        }
+
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    // Size of the frame's own header, if any, on top of its records
+    pub fn overhead(&self) -> usize {
+        match self {
+            Entry::Record(_) => 0,
+            Entry::Batch(_) => HEADER_SIZE,
+        }
+    }
+
+    // Read the next entry from a data file stream.
+    //
+    // Returns `Ok(None)` on a clean end of file, `UnexpectedEof` if the stream
+    // ends in the middle of an entry and `InvalidData` on a checksum mismatch.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Entry>> {
+        let mut header = [0; HEADER_SIZE];
+        let read = read_full(reader, &mut header)?;
+        if read == 0 {
+            return Ok(None);
+        } else if read < HEADER_SIZE {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let header = Header::parse(&header);
+        let mut body = Vec::new();
+        reader
+            .take(header.body_len() as u64)
+            .read_to_end(&mut body)?;
+        if body.len() < header.body_len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        if !header.batch {
+            return header
+                .finish(&body)
+                .map(|record| Some(Entry::Record(record)));
+        }
+
+        header.verify(&body)?;
+        let mut records = vec![];
+        let mut rest = &body[..];
+        while !rest.is_empty() {
+            // The frame is complete, so a record cut short inside it is
+            // corruption rather than a torn write
+            if rest.len() < HEADER_SIZE {
+                return Err(invalid_data("batch ends in the middle of a record"));
+            }
+            let header = Header::parse(&rest[..HEADER_SIZE]);
+            let record_size = HEADER_SIZE + header.body_len();
+            if header.batch || rest.len() < record_size {
+                return Err(invalid_data("malformed record in batch"));
+            }
+            records.push(header.finish(&rest[HEADER_SIZE..record_size])?);
+            rest = &rest[record_size..];
+        }
+        Ok(Some(Entry::Batch(records)))
+    }
+}
+
+// Whether the `len` bytes from a damaged entry to the end of the newest
+// file are what an interrupted append leaves behind: the start of one entry,
+// maybe padded with zeros or garbage by the file system, but no intact record
+// after it. Finding one means the damage is in front of data that was fully
+// written, which must not be cut off.
+//
+// The tail is read through `read_at(buf, offset)`, offset from the start of
+// the damage, a chunk at a time. A tail longer than any entry can be is
+// never a torn write, so it is not read at all.
+pub fn is_torn_tail(
+    len: u64,
+    read_at: impl FnMut(&mut [u8], u64) -> io::Result<()>,
+) -> io::Result<bool> {
+    if len > MAX_ENTRY_SIZE {
+        return Ok(false);
+    }
+    let mut tail = Tail {
+        len,
+        read_at,
+        chunk: vec![],
+        chunk_start: 0,
+    };
+
+    // A torn batch still holds the complete records written before the crash
+    let mut start = 1;
+    if tail.header_at(0)?.is_some_and(|header| header.batch) {
+        start = HEADER_SIZE as u64;
+        while let Some(len) = tail.intact_record_at(start)? {
+            start += len;
+        }
+    }
+    for offset in start..len {
+        if tail.intact_record_at(offset)?.is_some() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// The tail of a data file being scanned by `is_torn_tail`, with the chunk of
+// it that was read last
+struct Tail<F> {
+    len: u64,
+    read_at: F,
+    chunk: Vec<u8>,
+    chunk_start: u64,
+}
+
+impl<F: FnMut(&mut [u8], u64) -> io::Result<()>> Tail<F> {
+    // The header at `offset`, if the tail is long enough to hold one
+    fn header_at(&mut self, offset: u64) -> io::Result<Option<Header>> {
+        if offset + HEADER_SIZE as u64 > self.len {
+            return Ok(None);
+        }
+        let chunk_end = self.chunk_start + self.chunk.len() as u64;
+        if offset < self.chunk_start || offset + HEADER_SIZE as u64 > chunk_end {
+            let size = (self.len - offset).min(TAIL_CHUNK_SIZE as u64) as usize;
+            self.chunk.resize(size, 0);
+            (self.read_at)(&mut self.chunk, offset)?;
+            self.chunk_start = offset;
+        }
+        let start = (offset - self.chunk_start) as usize;
+        Ok(Some(Header::parse(&self.chunk[start..start + HEADER_SIZE])))
+    }
+
+    // The length of the record at `offset`, if it is complete and passes its
+    // checksum. The body is checksummed a chunk at a time.
+    fn intact_record_at(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        let header = match self.header_at(offset)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let body_len = header.body_len() as u64;
+        if header.batch || HEADER_SIZE as u64 + body_len > self.len - offset {
+            return Ok(None);
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header.raw);
+        let mut buf = vec![0; body_len.min(TAIL_CHUNK_SIZE as u64) as usize];
+        let mut position = offset + HEADER_SIZE as u64;
+        let end = position + body_len;
+        while position < end {
+            let size = buf.len().min((end - position) as usize);
+            (self.read_at)(&mut buf[..size], position)?;
+            hasher.update(&buf[..size]);
+            position += size as u64;
+        }
+        Ok((hasher.finalize() == header.crc).then_some(HEADER_SIZE as u64 + body_len))
+    }
+}
+
+// The key_size field of a record's header
+fn key_size_field(key_len: usize, expires: bool) -> u32 {
+    key_len as u32 | if expires { EXPIRES } else { 0 }
+}
+
+struct Header {
+    crc: u32,
+    raw: [u8; HEADER_SIZE - 4], // the checksummed part of the header
+    timestamp: u64,
+    key_size: usize,
+    value_size: Option<usize>,
+    batch: bool,   // for a batch, value_size is the size of its records
+    expires: bool, // whether the body starts with an expiry time
+}
+
+impl Header {
+    fn parse(buf: &[u8]) -> Header {
+        let u32_at = |i: usize| u32::from_le_bytes(buf[i..i + 4].try_into().unwrap());
+        let key_size = u32_at(12);
+        let value_size = u32_at(16);
+        let batch = key_size == BATCH;
+        let expires = !batch && key_size & EXPIRES != 0;
+
+        Header {
+            crc: u32_at(0),
+            raw: buf[4..HEADER_SIZE].try_into().unwrap(),
+            timestamp: u64::from_le_bytes(buf[4..12].try_into().unwrap()),
+            key_size: if batch {
+                0
+            } else {
+                (key_size & !EXPIRES) as usize
+            },
+            value_size: (batch || value_size != TOMBSTONE).then_some(value_size as usize),
+            batch,
+            expires,
+        }
+    }
+
+    fn body_len(&self) -> usize {
+        let expires_at_size = if self.expires { 8 } else { 0 };
+        expires_at_size + self.key_size + self.value_size.unwrap_or(0)
+    }
+
+    // Verify the checksum over header + body
+    fn verify(&self, body: &[u8]) -> io::Result<()> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.raw);
+        hasher.update(body);
+        if hasher.finalize() != self.crc {
+            return Err(invalid_data("record checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    // Verify the checksum and split the body into key and value
+    fn finish(self, body: &[u8]) -> io::Result<Record> {
+        self.verify(body)?;
+
+        let (expires_at, body) = if self.expires {
+            let (expires_at, body) = body.split_at(8);
+            (
+                Some(u64::from_le_bytes(expires_at.try_into().unwrap())),
+                body,
+            )
+        } else {
+            (None, body)
+        };
+        let (key, value) = body.split_at(self.key_size);
+        Ok(Record {
+            timestamp: self.timestamp,
+            key: key.to_vec(),
+            value: self.value_size.map(|_| value.to_vec()),
+            expires_at,
+        })
+    }
+}
+
+// Like `read_exact`, but reports how many bytes were read instead of failing
+// when the stream ends early
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The longest key, with or without an expiry time, reads back as a
+    // record rather than as a batch
+    #[test]
+    fn longest_key_is_not_a_batch() {
+        for expires in [false, true] {
+            let mut buf = [0; HEADER_SIZE];
+            buf[12..16].copy_from_slice(&key_size_field(MAX_KEY_SIZE, expires).to_le_bytes());
+            let header = Header::parse(&buf);
+            assert!(!header.batch);
+            assert_eq!(header.expires, expires);
+            assert_eq!(header.key_size, MAX_KEY_SIZE);
+        }
+    }
+}
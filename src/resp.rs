@@ -0,0 +1,503 @@
+// A server speaking RESP2, the Redis protocol, so that redis-cli and Redis
+// client libraries can use a `KvStore`. It understands:
+//
+// GET key
+// SET key value [EX seconds | PX milliseconds] [NX | XX]
+// DEL key [key ...]
+// EXISTS key [key ...]
+// KEYS pattern
+// SCAN cursor [MATCH pattern] [COUNT count]
+// PING [message]
+// INFO [section]
+// QUIT
+//
+// Commands come either as arrays of bulk strings, which is what clients send,
+// or inline as a line of space-separated words, which is what a person types
+// into telnet. As in Redis, a SCAN cursor is a number; here it is only valid
+// on the connection that started the scan.
+
+use crate::{
+    server::serve_connections, KvError, KvStore, KvsEngine, NaiveThreadPool, Result, ThreadPool,
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    ops::Bound,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Same limits as Redis puts on a request
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+// How many unfinished scans a connection remembers; starting one more forgets
+// the oldest, whose cursor becomes invalid
+const MAX_SCANS: usize = 64;
+
+pub struct RespServer<P: ThreadPool = NaiveThreadPool> {
+    store: KvStore,
+    pool: P,
+}
+
+impl RespServer {
+    pub fn new(store: KvStore) -> RespServer {
+        RespServer::with_pool(store, NaiveThreadPool)
+    }
+}
+
+// Serve each connection as one job of `pool`, see `KvsServer::with_pool` and
+// `server::serve_connections`
+impl<P: ThreadPool> RespServer<P> {
+    pub fn with_pool(store: KvStore, pool: P) -> RespServer<P> {
+        RespServer { store, pool }
+    }
+
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let store = self.store;
+        serve_connections(listener, &self.pool, move |stream| {
+            Connection::new(store.clone()).handle(stream)
+        })
+    }
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>), // None for the null bulk string
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK")
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(message) => write!(writer, "+{}\r\n", message),
+            Reply::Error(message) => write!(writer, "-{}\r\n", message),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                for reply in replies {
+                    reply.write_to(writer)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// What a command can fail with, besides the store's own errors
+enum CommandError {
+    Syntax,
+    NotInteger,
+    WrongArity(String),
+    Other(String),
+}
+
+impl From<CommandError> for Reply {
+    fn from(e: CommandError) -> Reply {
+        Reply::Error(match e {
+            CommandError::Syntax => "ERR syntax error".to_owned(),
+            CommandError::NotInteger => "ERR value is not an integer or out of range".to_owned(),
+            CommandError::WrongArity(command) => {
+                format!("ERR wrong number of arguments for '{}' command", command)
+            }
+            CommandError::Other(message) => format!("ERR {}", message),
+        })
+    }
+}
+
+impl From<KvError> for CommandError {
+    fn from(e: KvError) -> CommandError {
+        CommandError::Other(e.to_string())
+    }
+}
+
+type CommandResult = std::result::Result<Reply, CommandError>;
+
+struct Connection {
+    store: KvStore,
+    scans: BTreeMap<u64, Vec<u8>>, // cursor -> the last key the scan returned
+    next_cursor: u64,
+}
+
+impl Connection {
+    fn new(store: KvStore) -> Connection {
+        Connection {
+            store,
+            scans: BTreeMap::new(),
+            next_cursor: 1,
+        }
+    }
+
+    fn handle(mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+
+        while let Some(args) = read_command(&mut reader)? {
+            if args.is_empty() {
+                continue;
+            }
+            let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+            let reply = self.execute(&name, &args[1..]).unwrap_or_else(Reply::from);
+            reply.write_to(&mut writer)?;
+
+            // Pipelined commands get their replies in one write
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if name == "quit" {
+                break;
+            }
+        }
+
+        writer.flush()
+    }
+
+    fn execute(&mut self, name: &str, args: &[Vec<u8>]) -> CommandResult {
+        let arity = |ok: bool| {
+            if ok {
+                Ok(())
+            } else {
+                Err(CommandError::WrongArity(name.to_owned()))
+            }
+        };
+
+        match name {
+            "ping" => {
+                arity(args.len() <= 1)?;
+                Ok(match args.first() {
+                    Some(message) => Reply::Bulk(Some(message.clone())),
+                    None => Reply::Simple("PONG"),
+                })
+            }
+            "quit" => Ok(Reply::ok()),
+            "get" => {
+                arity(args.len() == 1)?;
+                Ok(Reply::Bulk(self.store.get_bytes(&args[0])?))
+            }
+            "set" => {
+                arity(args.len() >= 2)?;
+                self.set(&args[0], &args[1], &args[2..])
+            }
+            "del" => {
+                arity(!args.is_empty())?;
+                let mut removed = 0;
+                for key in args {
+                    match self.store.remove_bytes(key) {
+                        Ok(()) => removed += 1,
+                        Err(KvError::KeyNotFound(_)) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Ok(Reply::Integer(removed))
+            }
+            "exists" => {
+                arity(!args.is_empty())?;
+                let found = args
+                    .iter()
+                    .filter(|key| self.store.metadata(key).is_some())
+                    .count();
+                Ok(Reply::Integer(found as i64))
+            }
+            "keys" => {
+                arity(args.len() == 1)?;
+                let keys = self
+                    .store
+                    .keys()
+                    .filter(|key| glob_match(&args[0], key))
+                    .map(|key| Reply::Bulk(Some(key)))
+                    .collect();
+                Ok(Reply::Array(keys))
+            }
+            "scan" => {
+                arity(!args.is_empty())?;
+                self.scan(&args[0], &args[1..])
+            }
+            "info" => {
+                arity(args.len() <= 1)?;
+                Ok(Reply::Bulk(Some(self.info().into_bytes())))
+            }
+            _ => Err(CommandError::Other(format!("unknown command '{}'", name))),
+        }
+    }
+
+    fn set(&self, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> CommandResult {
+        let mut ttl = None;
+        let mut exists = None;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" if exists.is_none() => exists = Some(false),
+                b"XX" if exists.is_none() => exists = Some(true),
+                unit @ (b"EX" | b"PX") if ttl.is_none() => {
+                    let amount = parse_integer(options.next().ok_or(CommandError::Syntax)?)?;
+                    // Like Redis, refuse a time that is not positive or whose
+                    // expiry, in milliseconds since the epoch, overflows
+                    let millis = match unit {
+                        b"EX" => amount.checked_mul(1000),
+                        _ => Some(amount),
+                    };
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |now| now.as_millis() as i64);
+                    match millis.filter(|&millis| millis > 0 && now.checked_add(millis).is_some()) {
+                        Some(millis) => ttl = Some(Duration::from_millis(millis as u64)),
+                        None => {
+                            return Err(CommandError::Other(
+                                "invalid expire time in 'set' command".to_owned(),
+                            ))
+                        }
+                    }
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        let set = self
+            .store
+            .set_if(key.to_vec(), value.to_vec(), ttl, exists)?;
+        Ok(if set { Reply::ok() } else { Reply::Bulk(None) })
+    }
+
+    fn scan(&mut self, cursor: &[u8], options: &[Vec<u8>]) -> CommandResult {
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = options.next().ok_or(CommandError::Syntax)?;
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = Some(value.clone()),
+                b"COUNT" => {
+                    count = usize::try_from(parse_integer(value)?)
+                        .ok()
+                        .filter(|&count| count >= 1)
+                        .ok_or(CommandError::Syntax)?;
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        // Cursor 0 starts a new scan; any other one resumes after the last key
+        // it returned
+        let cursor = u64::try_from(parse_integer(cursor)?).map_err(|_| CommandError::NotInteger)?;
+        let start = match cursor {
+            0 => Bound::Unbounded,
+            _ => match self.scans.remove(&cursor) {
+                Some(last) => Bound::Excluded(last),
+                None => return Err(CommandError::Other("invalid cursor".to_owned())),
+            },
+        };
+
+        // Like in Redis, COUNT is the amount of work done rather than the
+        // number of keys returned, which MATCH may filter down
+        let batch: Vec<Vec<u8>> = self
+            .store
+            .range_keys((start, Bound::Unbounded))
+            .take(count)
+            .collect();
+        let next_cursor = match batch.last() {
+            Some(last) if batch.len() == count => {
+                let next_cursor = self.next_cursor;
+                self.next_cursor += 1;
+                // Cursors only grow, so the first one is the oldest
+                if self.scans.len() == MAX_SCANS {
+                    self.scans.pop_first();
+                }
+                self.scans.insert(next_cursor, last.clone());
+                next_cursor
+            }
+            _ => 0,
+        };
+
+        let keys = batch
+            .into_iter()
+            .filter(|key| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| glob_match(pattern, key))
+            })
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+        Ok(Reply::Array(vec![
+            Reply::Bulk(Some(next_cursor.to_string().into_bytes())),
+            Reply::Array(keys),
+        ]))
+    }
+
+    fn info(&self) -> String {
+        let keys = self.store.keys().count();
+        format!(
+            "# Server\r\nkvs_version:{}\r\n\r\n# Keyspace\r\ndb0:keys={}\r\n",
+            env!("CARGO_PKG_VERSION"),
+            keys
+        )
+    }
+}
+
+fn parse_integer(arg: &[u8]) -> std::result::Result<i64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or(CommandError::NotInteger)
+}
+
+// Read the next command as its list of arguments. Returns `Ok(None)` once the
+// client closed the connection.
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    // An inline command
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let len = parse_len(&line[1..], MAX_ARRAY_LEN)?;
+    let mut args = Vec::with_capacity(len);
+    for _ in 0..len {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected a bulk string"));
+        }
+        let len = parse_len(&line[1..], MAX_BULK_LEN)?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// Read a line without its CRLF (or bare LF, for inline commands)
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    reader
+        .take(MAX_INLINE_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("line is too long or not terminated"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    )
+}
+
+fn unexpected_eof() -> io::Error {
+    io::ErrorKind::UnexpectedEof.into()
+}
+
+// Redis-style glob matching: `*` matches any run of bytes, `?` any one byte,
+// `[abc]`, `[^abc]` and `[a-z]` a byte from (or not from) a set, and `\`
+// escapes the byte after it
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Where to resume after the most recent `*` if what follows it fails
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+            continue;
+        }
+        if let Some(next) = match_one(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+            continue;
+        }
+        match star {
+            // Let the `*` swallow one more byte and try again
+            Some((star_p, star_t)) => {
+                star = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// Match `byte` against the single-byte pattern element at `p`, returning the
+// position of the element after it on success
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = matches!(pattern.get(i), Some(b'^') | Some(b'!'));
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == byte;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (low, high) = (
+                        pattern[i].min(pattern[i + 2]),
+                        pattern[i].max(pattern[i + 2]),
+                    );
+                    matched |= (low..=high).contains(&byte);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == byte;
+                    i += 1;
+                }
+            }
+            // An unclosed `[` matches like Redis: up to the end of the pattern
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        literal => (literal == byte).then_some(p + 1),
+    }
+}
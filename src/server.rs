@@ -0,0 +1,175 @@
+// A TCP server giving remote `KvsClient`s access to one engine. Each
+// connection is served as one job of the server's thread pool, by default on
+// a thread of its own; the engine is shared between them through its cheap
+// clones.
+//
+// Clients can also have a `KvStore` backed up while the server holds it, but
+// only into a directory the server was given with `KvsServer::backup_dir`.
+
+use crate::{
+    protocol::{self, Request, Response},
+    KvError, KvStore, KvsEngine, NaiveThreadPool, Result, ThreadPool,
+};
+use std::{
+    fmt::Display,
+    io::{self, BufReader, BufWriter},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Component, Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+// How long the accept loops wait after a failed accept before trying again
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+pub struct KvsServer<E: KvsEngine, P: ThreadPool = NaiveThreadPool> {
+    engine: E,
+    pool: P,
+    backups: Option<Backups>, // None refuses every backup request
+}
+
+// Where backups go and the store they are taken of
+#[derive(Clone)]
+struct Backups {
+    dir: PathBuf,
+    store: KvStore,
+}
+
+impl<E: KvsEngine> KvsServer<E> {
+    pub fn new(engine: E) -> KvsServer<E> {
+        KvsServer::with_pool(engine, NaiveThreadPool)
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+    // Serve each connection as one job of `pool` rather than on a thread of
+    // its own, which bounds the number of threads when the pool does
+    pub fn with_pool(engine: E, pool: P) -> KvsServer<E, P> {
+        KvsServer {
+            engine,
+            pool,
+            backups: None,
+        }
+    }
+
+    // `run` listens on `addr`, `serve` on a listener that is already bound;
+    // both serve clients for good, see `serve_connections`
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let engine = self.engine;
+        let backups = self.backups;
+        serve_connections(listener, &self.pool, move |stream| {
+            handle_connection(engine.clone(), backups.as_ref(), stream)
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsServer<KvStore, P> {
+    // Let clients back the store up with `KvsClient::backup`, each backup
+    // into a new directory under `dir`, see `backup_path`. Only a `KvStore`
+    // can be backed up, and without this every request is refused.
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> KvsServer<KvStore, P> {
+        self.backups = Some(Backups {
+            dir: dir.into(),
+            store: self.engine.clone(),
+        });
+        self
+    }
+}
+
+// Where the backup a client named `name` goes under the server's backup
+// directory. The name comes from the network, so it must not lead out of the
+// directory: only a relative path made of plain names, without `..`, `.` or
+// a root, is accepted.
+pub(crate) fn backup_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let path = Path::new(name);
+    let plain = |component| matches!(component, Component::Normal(_));
+    if name.is_empty() || !path.components().all(plain) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "backup name `{}` must be a relative path without `..`",
+                name
+            ),
+        )
+        .into());
+    }
+    Ok(dir.join(path))
+}
+
+// The error a backup request gets from a server without a backup directory
+pub(crate) fn backups_disabled() -> KvError {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "backups are disabled on this server, see `kvs-server --backup-dir`",
+    )
+    .into()
+}
+
+// The accept loop of the blocking servers, behind their `run`, which listens
+// on an address, and `serve`, which takes a listener that is already bound,
+// e.g. to port 0. Each connection is passed to `handle` as one job of `pool`.
+// Clients are served for as long as the process runs: a failed accept, e.g.
+// because the client reset the connection or too many files are open, is
+// logged and the loop goes on after `ACCEPT_BACKOFF`, so an error that
+// persists does not spin.
+pub(crate) fn serve_connections<P, F, E>(listener: TcpListener, pool: &P, handle: F) -> Result<()>
+where
+    P: ThreadPool,
+    F: Fn(TcpStream) -> std::result::Result<(), E> + Clone + Send + 'static,
+    E: Display,
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Error accepting a connection: {}", e);
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        pool.spawn(move || {
+            let peer = stream.peer_addr();
+            if let Err(e) = handle(stream) {
+                log::error!("Error serving {:?}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(
+    engine: impl KvsEngine,
+    backups: Option<&Backups>,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+
+    while let Some(request) = protocol::read_frame(&mut reader)? {
+        log::debug!("Received {:?}", request);
+        let response = match request {
+            Request::Set { key, value } => engine.set(key, value).map(|_| None),
+            Request::Get { key } => engine.get(key),
+            Request::Remove { key } => engine.remove(key).map(|_| None),
+            Request::Backup { name } => match backups {
+                Some(backups) => backup_path(&backups.dir, &name)
+                    .and_then(|dest| backups.store.checkpoint(dest))
+                    .map(|_| None),
+                None => Err(backups_disabled()),
+            },
+        };
+        let response = match response {
+            Ok(value) => Response::Ok(value),
+            Err(KvError::KeyNotFound(key)) => Response::KeyNotFound(key),
+            Err(e) => Response::Err(e.to_string()),
+        };
+        protocol::write_frame(&mut writer, &response)?;
+    }
+
+    Ok(())
+}
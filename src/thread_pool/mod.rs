@@ -0,0 +1,48 @@
+use crate::Result;
+use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
+};
+
+mod naive;
+mod shared_queue;
+mod work_stealing;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
+
+// Runs the jobs of a server, e.g. one per client connection, so that the
+// number of threads can be bounded independently of the number of clients.
+//
+// A job that panics must not take the pool down with it: later jobs still
+// run. Dropping a pool lets the jobs already spawned finish, and waits for
+// them where the pool owns its threads.
+pub trait ThreadPool: Send + Sync + 'static + Sized {
+    // Start a pool of `threads` threads. Implementations that do not keep
+    // threads around may ignore the number.
+    fn new(threads: usize) -> Result<Self>;
+
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F);
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Run `job` on the current worker thread, catching a panic so the worker
+// stays alive. The panic message has already been printed by the panic hook.
+fn run_job(job: Job) {
+    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+        log::error!("A job of the thread pool panicked");
+    }
+}
+
+fn check_threads(threads: usize) -> Result<()> {
+    if threads == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a thread pool needs at least one thread",
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,18 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+// Not a pool at all: every job gets a thread of its own, which exits when the
+// job is done. There is no bound on the number of threads, and a panicking
+// job only takes its own thread down.
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: usize) -> Result<NaiveThreadPool> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        thread::spawn(job);
+    }
+}
@@ -0,0 +1,70 @@
+use super::{check_threads, run_job, Job, ThreadPool};
+use crate::Result;
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+// A fixed number of threads taking jobs from one shared queue, in the order
+// they were spawned. Every worker takes the queue's lock to receive a job, so
+// under many short jobs they contend on it.
+pub struct SharedQueueThreadPool {
+    sender: Option<Sender<Job>>, // only None while dropping
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: usize) -> Result<SharedQueueThreadPool> {
+        check_threads(threads)?;
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let mut workers = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            let worker = thread::Builder::new()
+                .name(format!("kvs-shared-queue-{}", i))
+                .spawn(move || work(&receiver))?;
+            workers.push(worker);
+        }
+
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers,
+        })
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // Workers catch panics, so they outlive the pool's handle
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(Box::new(job))
+            .expect("the workers of the pool are gone");
+    }
+}
+
+// Run jobs until the pool is dropped and the queue is empty. A panicking job
+// never holds the lock, so it cannot poison it.
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => run_job(job),
+            Err(_) => return,
+        }
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // Closing the channel tells the workers to exit once it is drained
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
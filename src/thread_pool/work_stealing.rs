@@ -0,0 +1,130 @@
+use super::{check_threads, run_job, Job, ThreadPool};
+use crate::Result;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+// A fixed number of threads, each with a queue of its own. Jobs are handed
+// out to the queues in turn; a worker runs the jobs at the front of its own
+// queue and, once it is empty, steals from the back of the others. Stealing
+// keeps one slow job from holding up the jobs queued behind it.
+//
+// While there is work, spawning a job and taking one only lock a single
+// queue, so workers contend less than on one shared queue. Only a worker
+// that finds every queue empty takes the pool's shared lock, to park until a
+// job is spawned, and `spawn` only takes it to wake a parked worker.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    next: AtomicUsize, // the queue the next job goes to
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    queues: Vec<Mutex<VecDeque<Job>>>, // one per worker
+    shutdown: Mutex<bool>,             // whether the pool was dropped
+    parked: AtomicUsize,               // workers waiting on `available`
+    available: Condvar,                // signalled when a job is spawned or the pool dropped
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: usize) -> Result<WorkStealingThreadPool> {
+        check_threads(threads)?;
+        let shared = Arc::new(Shared {
+            queues: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            shutdown: Mutex::new(false),
+            parked: AtomicUsize::new(0),
+            available: Condvar::new(),
+        });
+
+        let mut workers = Vec::with_capacity(threads);
+        for i in 0..threads {
+            let shared = shared.clone();
+            let worker = thread::Builder::new()
+                .name(format!("kvs-work-stealing-{}", i))
+                .spawn(move || shared.work(i))?;
+            workers.push(worker);
+        }
+
+        Ok(WorkStealingThreadPool {
+            shared,
+            next: AtomicUsize::new(0),
+            workers,
+        })
+    }
+
+    fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let queues = &self.shared.queues;
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % queues.len();
+        queues[index].lock().unwrap().push_back(Box::new(job));
+
+        // A worker counts itself as parked before it looks at the queues a
+        // last time, so either it finds the job or we see it parked. Taking
+        // the lock waits until it actually waits, so the signal is not lost.
+        if self.shared.parked.load(Ordering::SeqCst) > 0 {
+            let _shutdown = self.shared.shutdown.lock().unwrap();
+            self.shared.available.notify_one();
+        }
+    }
+}
+
+impl Shared {
+    // Run jobs until the pool is dropped and every queue is empty
+    fn work(&self, index: usize) {
+        loop {
+            let job = match self.find_job(index) {
+                Some(job) => job,
+                None => match self.park(index) {
+                    Some(job) => job,
+                    None => return,
+                },
+            };
+            run_job(job);
+        }
+    }
+
+    // Wait until a job can be taken, or return `None` once the pool is
+    // dropped and there is none left
+    fn park(&self, index: usize) -> Option<Job> {
+        let mut shutdown = self.shutdown.lock().unwrap();
+        self.parked.fetch_add(1, Ordering::SeqCst);
+        let job = loop {
+            if let Some(job) = self.find_job(index) {
+                break Some(job);
+            }
+            if *shutdown {
+                break None;
+            }
+            shutdown = self.available.wait(shutdown).unwrap();
+        };
+        self.parked.fetch_sub(1, Ordering::SeqCst);
+        job
+    }
+
+    fn find_job(&self, index: usize) -> Option<Job> {
+        if let Some(job) = self.queues[index].lock().unwrap().pop_front() {
+            return Some(job);
+        }
+        let len = self.queues.len();
+        (1..len).find_map(|offset| {
+            self.queues[(index + offset) % len]
+                .lock()
+                .unwrap()
+                .pop_back()
+        })
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        *self.shared.shutdown.lock().unwrap() = true;
+        self.shared.available.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,8 +1,25 @@
+// The CLI tests inherited from the original suite pass their arguments as
+// borrowed arrays; keep them as they were written
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    AsyncKvStore, AsyncKvsServer, HttpServer, KeyMetadata, KvError, KvStore, KvsClient, KvsEngine,
+    KvsServer, MemoryStore, NaiveThreadPool, Options, RespServer, Result, SharedQueueThreadPool,
+    SyncPolicy, ThreadPool, WorkStealingThreadPool, WriteBatch,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::process::Command;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{self, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -65,7 +82,7 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
@@ -94,7 +111,7 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
@@ -181,7 +198,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +208,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +219,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +228,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +240,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +256,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +264,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,14 +276,23 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
+    // Compaction runs in the background, so a file may be deleted between
+    // listing the directory and reading its metadata
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
         let len: walkdir::Result<u64> = entries
             .map(|res| {
-                res.and_then(|entry| entry.metadata())
+                match res
+                    .and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
+                {
+                    Err(e) if e.io_error().map(|e| e.kind()) == Some(io::ErrorKind::NotFound) => {
+                        Ok(0)
+                    }
+                    len => len,
+                }
             })
             .sum();
         len.expect("fail to get directory size")
@@ -289,7 +315,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +325,2421 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A flipped bit inside a record should be reported as corruption on open
+// instead of being replayed as garbage.
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Damage the value of the first record, which the second one follows
+    let log = temp_dir.path().join("0.log");
+    let mut bytes = fs::read(&log)?;
+    bytes[29] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { file_id, .. }) => assert_eq!(file_id, 0),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption was not detected"),
+    }
+
+    Ok(())
+}
+
+// Corruption of a record that is already indexed should surface on `get`.
+#[test]
+fn detect_corrupted_record_on_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let log = temp_dir.path().join("0.log");
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvError::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+// Compaction should leave a hint file next to the compacted data file, and
+// `open` should rebuild the key_dir from it without reading the values.
+#[test]
+fn open_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // The overwrite triggers a compaction into 1.log
+    assert!(temp_dir.path().join("1.hint").exists());
+
+    // Damage the value bytes: `open` only reads the hint so it still succeeds,
+    // and the checksum catches the damage on `get`
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvError::Corruption { .. })
+    ));
+
+    Ok(())
+}
+
+// A missing or damaged hint file falls back to replaying the data file.
+#[test]
+fn open_with_invalid_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let hint = temp_dir.path().join("1.hint");
+    let mut bytes = fs::read(&hint)?;
+    bytes[0] ^= 0xff;
+    fs::write(&hint, bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    fs::remove_file(&hint)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes, not just UTF-8 text.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let key = vec![0, 159, 146, 150, b'\n', 0];
+    let value = vec![255, 0, b'"', b'\\', 128];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set_bytes(b"empty".to_vec(), vec![])?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get_bytes(b"empty")?, Some(vec![]));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    assert_eq!(store.get_bytes(b"empty")?, Some(vec![]));
+
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    assert!(store.remove_bytes(&key).is_err());
+
+    Ok(())
+}
+
+// The string wrappers report values that are not valid UTF-8.
+#[test]
+fn get_non_utf8_value_as_string() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_bytes(b"key1".to_vec(), vec![0xff, 0xfe])?;
+    assert!(matches!(
+        store.get("key1".to_owned()),
+        Err(KvError::Utf8(_))
+    ));
+
+    Ok(())
+}
+
+// `kvs --engine memory` runs against a fresh in-memory engine.
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // Nothing is persisted between invocations, nor written to the directory
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "memory", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+fn exercise_engine(engine: &impl KvsEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.set_bytes(vec![0, 255], vec![1, 2, 3])?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get_bytes(&[0, 255])?, Some(vec![1, 2, 3]));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+
+    Ok(())
+}
+
+// Every engine behaves the same through the `KvsEngine` trait.
+#[test]
+fn engines_share_behaviour() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise_engine(&KvStore::open(temp_dir.path())?)?;
+    exercise_engine(&MemoryStore::new())?;
+    Ok(())
+}
+
+// Clones of the store can be written from many threads at once.
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..100 {
+                    let key = format!("key{}", i);
+                    store.set(key, format!("value{}", thread_id)).unwrap();
+                    store
+                        .set(format!("key{}-{}", thread_id, i), "x".to_owned())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..100 {
+        assert!(store.get(format!("key{}", i))?.is_some());
+        for thread_id in 0..8 {
+            let key = format!("key{}-{}", thread_id, i);
+            assert_eq!(store.get(key)?, Some("x".to_owned()));
+        }
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        for thread_id in 0..8 {
+            let key = format!("key{}-{}", thread_id, i);
+            assert_eq!(store.get(key)?, Some("x".to_owned()));
+        }
+    }
+
+    Ok(())
+}
+
+// Readers on other threads always see a complete value, including while
+// compaction moves records around underneath them.
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    for i in 0..100 {
+                        let value = store.get(format!("key{}", i)).unwrap();
+                        assert_eq!(value, Some(format!("value{}", i)));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // Overwriting with the same values keeps triggering compaction
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Writes carry on while the background compaction merges the old files, and
+// whatever they changed in the meantime wins over the merged copies.
+#[test]
+fn compaction_with_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter))?;
+    }
+
+    for key_id in 0..100 {
+        let expected = (key_id != 49).then(|| "49".to_owned());
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    // Dropping the store waits for the compaction in progress, so the
+    // directory can be reopened right away
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let expected = (key_id != 49).then(|| "49".to_owned());
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+fn log_file_count(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .unwrap()
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "log")
+        })
+        .count()
+}
+
+// A read-only store serves reads but refuses writes, and never writes to its
+// directory.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let files = log_file_count(temp_dir.path());
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+    assert_eq!(log_file_count(temp_dir.path()), files);
+
+    Ok(())
+}
+
+// Without `create_if_missing` (and in read-only mode) a missing directory is
+// an error instead of a fresh store.
+#[test]
+fn open_missing_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+
+    assert!(KvStore::open_with(&missing, Options::new().create_if_missing(false)).is_err());
+    assert!(KvStore::open_with(&missing, Options::new().read_only(true)).is_err());
+    assert!(!missing.exists());
+
+    KvStore::open_with(&missing, Options::new())?;
+    assert!(missing.exists());
+
+    Ok(())
+}
+
+// Compaction does not run until the garbage passes both the absolute
+// threshold and the garbage ratio.
+#[test]
+fn compaction_thresholds() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .compaction_threshold(10 * 1024)
+        .compaction_ratio(0.5);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    // Every record is overwritten once: about half the data is garbage,
+    // but not enough bytes yet
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    assert_eq!(log_file_count(temp_dir.path()), 1);
+
+    // Plenty of garbage bytes, but well under half of the data
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..400 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    assert_eq!(log_file_count(temp_dir.path()), 2);
+
+    // Past both limits
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+    let hints = fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "hint")
+        })
+        .count();
+    assert!(hints > 0);
+
+    Ok(())
+}
+
+// Run `writes` against a store in `dir` from a child process, which then
+// aborts so that none of the store's destructors run, as if it had been
+// killed right after the last write returned. The child is this test binary
+// re-running `test_name`, which lands back here and takes the first branch.
+fn open_and_crash(
+    test_name: &str,
+    dir: &Path,
+    sync_policy: SyncPolicy,
+    writes: impl Fn(&KvStore),
+) -> Result<()> {
+    if let Some(crash_dir) = env::var_os("KVS_CRASH_DIR") {
+        let options = Options::new().sync_policy(sync_policy);
+        let store = KvStore::open_with(crash_dir, options)?;
+        writes(&store);
+        process::abort();
+    }
+
+    let output = Command::new(env::current_exe()?)
+        .args([test_name, "--exact"])
+        .env("KVS_CRASH_DIR", dir)
+        .output()?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+// Acknowledged writes survive the process going away.
+fn check_writes_survive_crash(test_name: &str, sync_policy: SyncPolicy) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    open_and_crash(test_name, temp_dir.path(), sync_policy, |store| {
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        store.remove("key1".to_owned()).unwrap();
+    })?;
+
+    // The lock died with the process
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn writes_survive_crash_sync_always() -> Result<()> {
+    check_writes_survive_crash("writes_survive_crash_sync_always", SyncPolicy::Always)
+}
+
+#[test]
+fn writes_survive_crash_sync_interval() -> Result<()> {
+    check_writes_survive_crash(
+        "writes_survive_crash_sync_interval",
+        SyncPolicy::Interval(Duration::from_millis(10)),
+    )
+}
+
+#[test]
+fn writes_survive_crash_sync_never() -> Result<()> {
+    check_writes_survive_crash("writes_survive_crash_sync_never", SyncPolicy::Never)
+}
+
+// `flush` and `sync` can be called at any time, including on a read-only store.
+#[test]
+fn explicit_flush_and_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    open_and_crash(
+        "explicit_flush_and_sync",
+        temp_dir.path(),
+        SyncPolicy::Never,
+        |store| {
+            store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+            store.flush().unwrap();
+            store.sync().unwrap();
+        },
+    )?;
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    store.flush()?;
+    store.sync()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The active file rolls over to a new one once it reaches `max_file_size`,
+// and every file stays readable, before and after a reopen.
+#[test]
+fn rotate_data_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .compaction_threshold(u64::MAX)
+        .max_file_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_file_count(temp_dir.path()) > 5);
+    for entry in fs::read_dir(temp_dir.path())? {
+        // a file goes past the limit by at most one record
+        assert!(entry?.metadata()?.len() < 1024 + 64);
+    }
+    for key_id in 0..200 {
+        let value = store.get(format!("key{}", key_id))?;
+        assert_eq!(value, Some(format!("value{}", key_id)));
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..200 {
+        let value = store.get(format!("key{}", key_id))?;
+        assert_eq!(value, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
+
+// Compaction merges the rotated files while more of them are being written.
+#[test]
+fn compaction_with_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .compaction_threshold(4 * 1024)
+        .max_file_size(1024);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A record torn by a crash in the middle of a write is cut off the end of the
+// newest file and the store opens with everything written before it.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let intact = fs::metadata(temp_dir.path().join("0.log"))?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("0.log");
+    let torn = fs::metadata(&log)?.len() - 3;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(torn)?;
+
+    // A read-only open skips the torn record without touching the file
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    assert_eq!(fs::metadata(&log)?.len(), torn);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&log)?.len(), intact);
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A crash can also leave a full-length record that fails its checksum, or
+// zeros, at the end of the newest file. Both are cut off like a short record.
+#[test]
+fn recover_damaged_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let intact = fs::metadata(temp_dir.path().join("0.log"))?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("0.log");
+    let mut bytes = fs::read(&log)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&log, &bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&log)?.len(), intact);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // The reopened store wrote to a new file, which is now the newest
+    let log = temp_dir.path().join("1.log");
+    let intact = fs::metadata(&log)?.len();
+    let mut bytes = fs::read(&log)?;
+    bytes.extend_from_slice(&[0; 40]);
+    fs::write(&log, &bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(fs::metadata(&log)?.len(), intact);
+
+    Ok(())
+}
+
+// Damage is told apart from a torn write the same way when the records are
+// larger than the part of the tail read at once.
+#[test]
+fn damaged_tail_with_large_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100 * 1024);
+    store.set("key1".to_owned(), value.clone())?;
+    let intact = fs::metadata(temp_dir.path().join("0.log"))?.len();
+    store.set("key2".to_owned(), value.clone())?;
+    drop(store);
+
+    // A bad checksum in the last record is a torn write
+    let log = temp_dir.path().join("0.log");
+    let bytes = fs::read(&log)?;
+    let mut damaged = bytes.clone();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 0xff;
+    fs::write(&log, &damaged)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&log)?.len(), intact);
+    drop(store);
+
+    // ... but not in the first one, which has an intact record after it
+    let log = temp_dir.path().join("1.log");
+    fs::remove_file(&log)?;
+    let mut damaged = bytes;
+    damaged[40] ^= 0xff;
+    fs::write(temp_dir.path().join("0.log"), &damaged)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { file_id, offset }) => assert_eq!((file_id, offset), (0, 0)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption was not detected"),
+    }
+
+    Ok(())
+}
+
+// A damaged header in the middle of the newest file can make its record look
+// like it runs past the end of the file. The intact records after it show it
+// is not a torn write, so the open fails and the file is left alone.
+#[test]
+fn corrupted_header_in_newest_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // Blow up the value_size of the first record
+    let log = temp_dir.path().join("0.log");
+    let mut bytes = fs::read(&log)?;
+    bytes[16..19].copy_from_slice(&[0xff; 3]);
+    fs::write(&log, &bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { file_id, offset }) => assert_eq!((file_id, offset), (0, 0)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption was not detected"),
+    }
+    assert_eq!(fs::read(&log)?, bytes);
+
+    Ok(())
+}
+
+// Only the newest file can have a torn tail; anywhere else an incomplete
+// record is corruption.
+#[test]
+fn torn_record_in_older_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("0.log");
+    let len = fs::metadata(&log)?.len();
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Corruption { file_id, .. }) => assert_eq!(file_id, 0),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption was not detected"),
+    }
+
+    Ok(())
+}
+
+// A compaction interrupted by a crash leaves only a temporary file, which
+// does not get in the way of the next open.
+#[test]
+fn recover_interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let tmp = temp_dir.path().join("1.log.tmp");
+    fs::write(&tmp, [1, 2, 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!tmp.exists());
+
+    Ok(())
+}
+
+// Only one writer at a time: a second open fails with the PID of the holder
+// until the first store (and every clone of it) is dropped.
+#[test]
+fn lock_store_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Locked(pid)) => assert_eq!(pid, Some(process::id())),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("directory was not locked"),
+    }
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), Options::new().read_only(true)),
+        Err(KvError::Locked(_))
+    ));
+
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Read-only stores share the lock with each other, but keep writers out.
+#[test]
+fn share_lock_between_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let reader1 = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    let reader2 = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert_eq!(reader1.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(reader2.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::Locked(None))
+    ));
+
+    drop(reader1);
+    drop(reader2);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+fn exercise_scans(store: &impl KvsEngine) -> Result<()> {
+    for key in ["a", "b1", "b2", "b3", "c"] {
+        store.set(key.to_owned(), format!("value-{}", key))?;
+    }
+    store.set_bytes(vec![0xff], b"ff".to_vec())?;
+    store.set_bytes(vec![0xff, 0xff], b"ffff".to_vec())?;
+    store.remove("b2".to_owned())?;
+
+    let keys = |scan: &mut dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>| {
+        scan.map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()
+    };
+
+    let pairs = store.range("b".."c").collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"b1".to_vec(), b"value-b1".to_vec()),
+            (b"b3".to_vec(), b"value-b3".to_vec()),
+        ]
+    );
+    assert_eq!(
+        keys(&mut store.range("b1"..="c"))?,
+        vec![b"b1".to_vec(), b"b3".to_vec(), b"c".to_vec()]
+    );
+    assert_eq!(
+        keys(&mut store.range::<&str>(..).rev().take(3))?,
+        vec![vec![0xff, 0xff], vec![0xff], b"c".to_vec()]
+    );
+    assert_eq!(
+        keys(&mut store.scan_prefix("b").rev())?,
+        vec![b"b3".to_vec(), b"b1".to_vec()]
+    );
+    assert_eq!(
+        keys(&mut store.scan_prefix([0xff]))?,
+        vec![vec![0xff], vec![0xff, 0xff]]
+    );
+    assert_eq!(keys(&mut store.scan_prefix(""))?.len(), 6);
+    assert!(store.range("c".."b").next().is_none());
+    assert!(store.range("b".."b").next().is_none());
+
+    // Both ends of the same scan meet in the middle without overlapping
+    let mut scan = store.scan_prefix("b");
+    assert_eq!(
+        scan.next().transpose()?.map(|(key, _)| key),
+        Some(b"b1".to_vec())
+    );
+    assert_eq!(
+        scan.next_back().transpose()?.map(|(key, _)| key),
+        Some(b"b3".to_vec())
+    );
+    assert!(scan.next().is_none());
+    assert!(scan.next_back().is_none());
+
+    Ok(())
+}
+
+// Range and prefix scans return pairs in key order, from either end.
+#[test]
+fn range_scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise_scans(&KvStore::open(temp_dir.path())?)?;
+    exercise_scans(&MemoryStore::new())?;
+    Ok(())
+}
+
+// A scan reads each value as it goes, so it keeps working while compaction
+// moves the records around.
+#[test]
+fn range_scan_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().compaction_threshold(0))?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+
+    let mut scan = store.range::<&str>(..);
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+        let (key, value) = scan.next().unwrap()?;
+        assert_eq!(key, format!("key{:03}", i).into_bytes());
+        assert_eq!(value, format!("value{}", i).into_bytes());
+    }
+    assert!(scan.next().is_none());
+
+    Ok(())
+}
+
+// Keys and their metadata come straight from the key_dir and point at the
+// records in the data files.
+#[test]
+fn keys_and_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Keep compaction from moving the records around
+    let options = Options::new().compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set("key1".to_owned(), "a longer value1".to_owned())?;
+    store.remove("key3".to_owned())?;
+    let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+    let keys: Vec<_> = store.keys().collect();
+    assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec()]);
+    let keys: Vec<_> = store.keys().rev().collect();
+    assert_eq!(keys, vec![b"key2".to_vec(), b"key1".to_vec()]);
+    assert_eq!(store.metadata("key3"), None);
+
+    let KeyMetadata {
+        file_id,
+        offset,
+        record_size,
+        timestamp,
+        expires_at,
+    } = store.metadata("key1").unwrap();
+    assert_eq!(expires_at, None);
+    // header, key and value
+    assert_eq!(record_size, 20 + 4 + 15);
+    assert!(before.as_millis() as u64 <= timestamp && timestamp <= after.as_millis() as u64);
+    let data = fs::read(temp_dir.path().join(format!("{}.log", file_id)))?;
+    let record = &data[offset as usize..offset as usize + record_size];
+    assert!(record.ends_with(b"key1a longer value1"));
+
+    // The metadata survives a reopen
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(
+        store.metadata("key1"),
+        Some(KeyMetadata {
+            file_id,
+            offset,
+            record_size,
+            timestamp,
+            expires_at
+        })
+    );
+
+    Ok(())
+}
+
+fn exercise_batch(store: &impl KvsEngine) -> Result<()> {
+    store.set("account".to_owned(), "100".to_owned())?;
+    store.set("index/old".to_owned(), "account".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("account", "50");
+    batch.remove("index/old");
+    batch.set("index/new", "account");
+    batch.remove("missing");
+    batch.set("index/new", b"account".to_vec());
+    assert_eq!(batch.len(), 5);
+    store.apply_batch(batch)?;
+
+    assert_eq!(store.get("account".to_owned())?, Some("50".to_owned()));
+    assert_eq!(store.get("index/old".to_owned())?, None);
+    assert_eq!(
+        store.get("index/new".to_owned())?,
+        Some("account".to_owned())
+    );
+    assert_eq!(store.get("missing".to_owned())?, None);
+
+    store.apply_batch(WriteBatch::new())?;
+
+    Ok(())
+}
+
+// A batch applies its sets and removes in order, and survives a reopen.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    exercise_batch(&KvStore::open(temp_dir.path())?)?;
+    exercise_batch(&MemoryStore::new())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("account".to_owned())?, Some("50".to_owned()));
+    assert_eq!(store.get("index/old".to_owned())?, None);
+    assert_eq!(
+        store.get("index/new".to_owned())?,
+        Some("account".to_owned())
+    );
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    let mut batch = WriteBatch::new();
+    batch.set("account", "0");
+    assert!(matches!(store.apply_batch(batch), Err(KvError::ReadOnly)));
+
+    Ok(())
+}
+
+// A batch torn by a crash is dropped as a whole, even the records of it that
+// made it to disk intact.
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().compaction_threshold(u64::MAX);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let intact = fs::metadata(temp_dir.path().join("0.log"))?.len();
+    let mut batch = WriteBatch::new();
+    batch.remove("key0");
+    for i in 1..10 {
+        batch.set(format!("key{}", i), format!("value{}", i));
+    }
+    store.apply_batch(batch)?;
+    drop(store);
+
+    let log = temp_dir.path().join("0.log");
+    let torn = fs::metadata(&log)?.len() - 3;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(torn)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+    assert_eq!(fs::metadata(&log)?.len(), intact);
+
+    Ok(())
+}
+
+// A transaction commits only if nothing it read has been written since, and
+// sees its own writes before that.
+#[test]
+fn transaction_conflicts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("balance".to_owned(), "100".to_owned())?;
+
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("balance".to_owned())?, Some("100".to_owned()));
+    txn.set("balance".to_owned(), "90".to_owned());
+    txn.set("log".to_owned(), "-10".to_owned());
+    assert_eq!(txn.get("balance".to_owned())?, Some("90".to_owned()));
+    txn.remove("log".to_owned());
+    assert_eq!(txn.get("log".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(store.get("balance".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("log".to_owned())?, None);
+
+    // Writing the same value again still counts as a change
+    let mut txn = store.transaction();
+    txn.get("balance".to_owned())?;
+    txn.set("balance".to_owned(), "0".to_owned());
+    store.set("balance".to_owned(), "90".to_owned())?;
+    match txn.commit() {
+        Err(KvError::Conflict(key)) => assert_eq!(key, "balance"),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+    assert_eq!(store.get("balance".to_owned())?, Some("90".to_owned()));
+
+    // So does a key that was missing when it was read
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("owner".to_owned())?, None);
+    txn.set("owner".to_owned(), "txn".to_owned());
+    store.set("owner".to_owned(), "other".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvError::Conflict(_))));
+
+    // Writes to keys that were not read do not conflict
+    let mut txn = store.transaction();
+    txn.get("balance".to_owned())?;
+    txn.set("owner".to_owned(), "txn".to_owned());
+    store.set("owner".to_owned(), "other".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("owner".to_owned())?, Some("txn".to_owned()));
+
+    Ok(())
+}
+
+// Retrying on conflict makes read-modify-write safe across threads, even while
+// compaction moves the records around.
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().compaction_threshold(0))?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = store.transaction();
+                        let counter: u64 = txn
+                            .get("counter".to_owned())
+                            .unwrap()
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        txn.set("counter".to_owned(), (counter + 1).to_string());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(KvError::Conflict(_)) => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+
+    Ok(())
+}
+
+// Conditional writes only happen when the current value is the expected one.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent(b"lease", b"node1")?);
+    assert!(!store.set_if_absent(b"lease", b"node2")?);
+    assert_eq!(store.get("lease".to_owned())?, Some("node1".to_owned()));
+
+    assert!(!store.compare_and_swap(b"lease", Some(b"node2"), Some(b"node3"))?);
+    assert!(!store.compare_and_swap(b"lease", None, Some(b"node3"))?);
+    assert!(store.compare_and_swap(b"lease", Some(b"node1"), Some(b"node3"))?);
+    assert_eq!(store.get("lease".to_owned())?, Some("node3".to_owned()));
+
+    assert!(!store.remove_if_equals(b"lease", b"node1")?);
+    assert!(store.remove_if_equals(b"lease", b"node3")?);
+    assert_eq!(store.get("lease".to_owned())?, None);
+    assert!(!store.remove_if_equals(b"lease", b"node3")?);
+
+    // Swapping no value for no value succeeds without writing anything
+    assert!(store.compare_and_swap(b"lease", None, None)?);
+    assert!(!store.compare_and_swap(b"other", Some(b"x"), None)?);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    assert!(matches!(
+        store.set_if_absent(b"lease", b"node1"),
+        Err(KvError::ReadOnly)
+    ));
+
+    Ok(())
+}
+
+// Of many threads racing to take the same lease, exactly one wins.
+#[test]
+fn concurrent_set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                (0..50)
+                    .filter(|i| {
+                        let key = format!("lease{}", i);
+                        let value = format!("{}", thread_id);
+                        store
+                            .set_if_absent(key.as_bytes(), value.as_bytes())
+                            .unwrap()
+                    })
+                    .count()
+            })
+        })
+        .collect();
+    let won: usize = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum();
+    assert_eq!(won, 50);
+
+    Ok(())
+}
+
+// Keys set with a time-to-live read as absent once it has passed, before and
+// after a reopen.
+#[test]
+fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl("key1", "short", Duration::from_millis(100))?;
+    store.set_with_ttl("key2", "long", Duration::from_secs(3600))?;
+    store.set_with_ttl("key3", "short", Duration::from_millis(100))?;
+    store.set("key3".to_owned(), "forever".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("short".to_owned()));
+    assert!(store.metadata("key1").unwrap().expires_at.is_some());
+    thread::sleep(Duration::from_millis(150));
+
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.metadata("key1"), None);
+    assert!(matches!(
+        store.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+    let keys: Vec<_> = store.keys().collect();
+    assert_eq!(keys, vec![b"key2".to_vec(), b"key3".to_vec()]);
+    assert_eq!(store.range::<&str>(..).rev().count(), 2);
+    assert_eq!(store.get("key2".to_owned())?, Some("long".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("forever".to_owned()));
+    assert!(store.set_if_absent(b"key1", b"again")?);
+    store.set_with_ttl("key1", "short", Duration::from_millis(100))?;
+    drop(store);
+
+    // An expired value must not bring back the one it overwrote
+    thread::sleep(Duration::from_millis(150));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("long".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("forever".to_owned()));
+
+    Ok(())
+}
+
+// Compaction drops expired keys and reclaims their space; the keys that have
+// not expired yet keep their expiry through the hint file.
+#[test]
+fn compaction_drops_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().compaction_threshold(0))?;
+    let value = "x".repeat(1000);
+    for i in 0..100 {
+        store.set_with_ttl(
+            format!("short{}", i),
+            value.clone(),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set_with_ttl("long", "value", Duration::from_secs(3600))?;
+    let expires_at = store.metadata("long").unwrap().expires_at;
+    thread::sleep(Duration::from_millis(150));
+
+    // Overwriting a key wakes up the compactor
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(
+        size < 10_000,
+        "expired keys were not reclaimed: {} bytes",
+        size
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short0".to_owned())?, None);
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.metadata("long").unwrap().expires_at, expires_at);
+
+    Ok(())
+}
+
+// A snapshot keeps reading the values as they were when it was taken, even
+// after compaction deleted the files they were in.
+#[test]
+fn read_from_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().compaction_threshold(0))?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+    let snapshot = store.snapshot();
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), "new".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.set("extra".to_owned(), "new".to_owned())?;
+    // Wait for the compactor to delete the file the old values are in
+    while temp_dir.path().join("0.log").exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    for i in 0..100 {
+        assert_eq!(snapshot.get(format!("key{}", i))?, Some("old".to_owned()));
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            (i > 0).then(|| "new".to_owned())
+        );
+    }
+    assert_eq!(snapshot.get("extra".to_owned())?, None);
+    assert_eq!(snapshot.range("key1".."key2").count(), 11);
+    assert_eq!(snapshot.keys().count(), 100);
+    assert!(matches!(
+        snapshot.set("key1".to_owned(), "x".to_owned()),
+        Err(KvError::ReadOnly)
+    ));
+
+    // The snapshot outlives the store it was taken from
+    drop(store);
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("old".to_owned()));
+
+    Ok(())
+}
+
+// A checkpoint taken while the store is being written and compacted opens on
+// its own with exactly the writes made before it.
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().compaction_threshold(0).max_file_size(1000);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..200 {
+        store.set(format!("key{}", i % 50), format!("value{}", i))?;
+    }
+    store.set_with_ttl("session", "token", Duration::from_secs(3600))?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..200 {
+                store
+                    .set(format!("key{}", i % 50), "after".to_owned())
+                    .unwrap();
+            }
+        })
+    };
+    let dest = backup_dir.path().join("checkpoint");
+    store.checkpoint(&dest)?;
+    writer.join().unwrap();
+
+    // The copy is a point in time during the second round of writes, which
+    // goes through the keys in order: some prefix of them has been rewritten
+    let copy = KvStore::open(&dest)?;
+    let after = (0..50)
+        .take_while(|i| copy.get(format!("key{}", i)).unwrap() == Some("after".to_owned()))
+        .count();
+    for i in after..50 {
+        assert_eq!(
+            copy.get(format!("key{}", i))?,
+            Some(format!("value{}", 150 + i))
+        );
+    }
+    assert_eq!(copy.get("session".to_owned())?, Some("token".to_owned()));
+
+    // Writing to the copy does not affect the original, nor the other way around
+    copy.set("key0".to_owned(), "copy".to_owned())?;
+    store.set("key1".to_owned(), "original".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("after".to_owned()));
+    assert_ne!(copy.get("key1".to_owned())?, Some("original".to_owned()));
+
+    assert!(store.checkpoint(&dest).is_err());
+
+    Ok(())
+}
+
+// A checkpoint of a snapshot holds the store as it was when the snapshot was
+// taken, even though the active file has grown since, and shares no file the
+// original still writes to.
+#[test]
+fn checkpoint_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "before".to_owned())?;
+    let snapshot = store.snapshot();
+    store.set("b".to_owned(), "after".to_owned())?;
+
+    let dest = backup_dir.path().join("checkpoint");
+    snapshot.checkpoint(&dest)?;
+    store.set("c".to_owned(), "later".to_owned())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(fs::metadata(temp_dir.path().join("0.log"))?.nlink(), 1);
+    }
+    let copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("a".to_owned())?, Some("before".to_owned()));
+    assert_eq!(copy.get("b".to_owned())?, None);
+    assert_eq!(copy.get("c".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs backup` writes a copy of the store in the current directory.
+#[test]
+fn cli_backup() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(&dest)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    // The destination must be empty
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("backup")
+        .arg(&dest)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("Fail to execute BACKUP command"));
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// With archiving on, compaction keeps the superseded files around and a store
+// can be restored to how it was at any moment since.
+#[test]
+fn archive_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().compaction_threshold(0).archive(true);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("important".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("account", "100");
+    batch.set("index", "account");
+    store.apply_batch(batch)?;
+    thread::sleep(Duration::from_millis(5));
+    let before_remove = now_millis();
+    thread::sleep(Duration::from_millis(5));
+
+    store.remove("important".to_owned())?;
+    store.set("account".to_owned(), "0".to_owned())?;
+    for i in 0..100 {
+        store.set("filler".to_owned(), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("archive").join("0.log").exists());
+    assert!(!temp_dir.path().join("0.log").exists());
+
+    let dest = restore_dir.path().join("before");
+    KvStore::restore(temp_dir.path(), &dest, before_remove)?;
+    let restored = KvStore::open(&dest)?;
+    assert_eq!(
+        restored.get("important".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(restored.get("account".to_owned())?, Some("100".to_owned()));
+    assert_eq!(
+        restored.get("index".to_owned())?,
+        Some("account".to_owned())
+    );
+    assert_eq!(restored.get("filler".to_owned())?, None);
+
+    let dest = restore_dir.path().join("now");
+    KvStore::restore(temp_dir.path(), &dest, now_millis())?;
+    let restored = KvStore::open(&dest)?;
+    assert_eq!(restored.get("important".to_owned())?, None);
+    assert_eq!(restored.get("account".to_owned())?, Some("0".to_owned()));
+    assert_eq!(
+        restored.get("filler".to_owned())?,
+        Some("value99".to_owned())
+    );
+
+    // The archive is no part of the store itself
+    drop(restored);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("important".to_owned())?, None);
+    assert!(KvStore::restore(temp_dir.path(), &dest, 0).is_err());
+
+    Ok(())
+}
+
+// `kvs restore --until` rebuilds the store in the current directory as it was
+// at that moment, from the files `--archive` kept.
+#[test]
+fn cli_restore() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = restore_dir.path().join("restored");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--archive", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(5));
+    let until = now_millis();
+    thread::sleep(Duration::from_millis(5));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--archive", "rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", "--until", &until.to_string()])
+        .arg(&dest)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", "--until", "not-a-timestamp"])
+        .arg(&dest)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Serve `engine` on a free local port and return its address
+fn spawn_server(engine: impl KvsEngine) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || KvsServer::new(engine).serve(listener));
+    addr
+}
+
+// Clients reach the store through the server, which reports errors back the
+// way the engine returned them.
+#[test]
+fn client_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let addr = spawn_server(store.clone());
+
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(key)) if key == "key1"
+    ));
+    store.set("key3".to_owned(), "local".to_owned())?;
+    assert_eq!(client.get("key3".to_owned())?, Some("local".to_owned()));
+
+    // Many clients at once
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    client.set(key.clone(), i.to_string()).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(i.to_string()));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("key7-19".to_owned())?, Some("19".to_owned()));
+
+    // Other errors come back as their message
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), Options::new().read_only(true))?;
+    let mut client = KvsClient::connect(spawn_server(store))?;
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(KvError::Server(message)) => assert_eq!(message, KvError::ReadOnly.to_string()),
+        other => panic!("expected a server error, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A client can back up the store of a running server, which holds the lock
+// that keeps `kvs backup` out, but only into the server's backup directory.
+#[test]
+fn client_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let server = KvsServer::new(store.clone()).backup_dir(backup_dir.path());
+    thread::spawn(move || server.serve(listener));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.backup("nightly/1")?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    // The backup only holds what was written before it
+    let backup = KvStore::open(backup_dir.path().join("nightly/1"))?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, None);
+
+    // The destination must be empty and inside the backup directory
+    let outside = temp_dir.path().join("outside");
+    for name in [
+        "nightly/1",
+        "",
+        ".",
+        "../outside",
+        outside.to_str().unwrap(),
+    ] {
+        assert!(
+            matches!(client.backup(name), Err(KvError::Server(_))),
+            "backup to `{}` was accepted",
+            name
+        );
+    }
+    assert!(!outside.exists());
+
+    // A server without a backup directory refuses them all
+    let mut client = KvsClient::connect(spawn_server(store))?;
+    assert!(matches!(client.backup("backup"), Err(KvError::Server(_))));
+    assert!(!temp_dir.path().join("backup").exists());
+
+    Ok(())
+}
+
+// Kills the child process when dropped, so a failing test does not leave the
+// server running
+struct ChildGuard(process::Child);
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Start `kvs-server` in `dir` on a free port and wait until it accepts
+// connections
+fn start_server_binary(dir: &Path, args: &[&str]) -> (ChildGuard, String) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr])
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let guard = ChildGuard(child);
+    for _ in 0..500 {
+        if TcpStream::connect(&addr).is_ok() {
+            return (guard, addr);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("kvs-server did not start");
+}
+
+// `kvs-client` prints the same output as `kvs` and exits with the same codes.
+#[test]
+fn cli_client_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, addr) = start_server_binary(temp_dir.path(), &[]);
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", &addr])
+            .current_dir(&temp_dir);
+        cmd
+    };
+
+    client(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["get", "key2"])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    client(&["rm", "key1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["rm", "key1"])
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+
+    // The server owns the directory for as long as it runs
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // ... and refuses backups unless it was given a directory for them
+    client(&["backup", "backup"])
+        .assert()
+        .failure()
+        .stdout(contains("Fail to execute BACKUP command"));
+}
+
+// `kvs-client backup` backs the store of a running `kvs-server` up into its
+// `--backup-dir`.
+#[test]
+fn cli_client_backup() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, addr) = start_server_binary(
+        temp_dir.path(),
+        &["--backup-dir", backup_dir.path().to_str().unwrap()],
+    );
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", &addr]);
+        cmd
+    };
+
+    client(&["set", "key1", "value1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["backup", "backup"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(backup_dir.path().join("backup"))
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["backup", "backup"])
+        .assert()
+        .failure()
+        .stdout(contains("Fail to execute BACKUP command"));
+    client(&["backup", "../backup"])
+        .assert()
+        .failure()
+        .stdout(contains("Fail to execute BACKUP command"));
+
+    // The memory engine has nothing to back up
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--backup-dir"])
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Without a server to talk to, `kvs-client` fails.
+#[test]
+fn cli_client_without_server() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .failure();
+}
+
+fn spawn_resp_server(store: KvStore) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || RespServer::new(store).serve(listener));
+    addr
+}
+
+// Send `command` as an array of bulk strings and check that the reply is
+// exactly `expected`
+fn assert_resp(stream: &mut TcpStream, command: &[&str], expected: &str) {
+    let mut request = format!("*{}\r\n", command.len());
+    for arg in command {
+        request += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), expected, "{:?}", command);
+}
+
+// The RESP server answers Redis commands the way Redis does.
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut stream = TcpStream::connect(spawn_resp_server(store.clone()))?;
+
+    assert_resp(&mut stream, &["PING"], "+PONG\r\n");
+    assert_resp(&mut stream, &["ping", "hello"], "$5\r\nhello\r\n");
+    assert_resp(&mut stream, &["SET", "key1", "value1"], "+OK\r\n");
+    assert_resp(&mut stream, &["GET", "key1"], "$6\r\nvalue1\r\n");
+    assert_resp(&mut stream, &["GET", "key2"], "$-1\r\n");
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // NX and XX
+    assert_resp(&mut stream, &["SET", "key1", "other", "NX"], "$-1\r\n");
+    assert_resp(&mut stream, &["SET", "key2", "value2", "XX"], "$-1\r\n");
+    assert_resp(&mut stream, &["SET", "key2", "value2", "nx"], "+OK\r\n");
+    assert_resp(&mut stream, &["SET", "key2", "new", "XX"], "+OK\r\n");
+    assert_resp(&mut stream, &["GET", "key2"], "$3\r\nnew\r\n");
+
+    // EX and PX
+    assert_resp(
+        &mut stream,
+        &["SET", "key3", "value3", "EX", "100"],
+        "+OK\r\n",
+    );
+    assert!(store.metadata("key3").unwrap().expires_at.is_some());
+    assert_resp(
+        &mut stream,
+        &["SET", "key4", "value4", "PX", "50"],
+        "+OK\r\n",
+    );
+    thread::sleep(Duration::from_millis(100));
+    assert_resp(&mut stream, &["GET", "key4"], "$-1\r\n");
+
+    assert_resp(
+        &mut stream,
+        &["EXISTS", "key1", "key2", "key4", "key1"],
+        ":3\r\n",
+    );
+    assert_resp(&mut stream, &["DEL", "key1", "key4", "key5"], ":1\r\n");
+    assert_resp(&mut stream, &["EXISTS", "key1"], ":0\r\n");
+
+    store.set("other".to_owned(), "x".to_owned())?;
+    assert_resp(
+        &mut stream,
+        &["KEYS", "key*"],
+        "*2\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["KEYS", "*[^2]"],
+        "*2\r\n$4\r\nkey3\r\n$5\r\nother\r\n",
+    );
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\n\r\n# Keyspace\r\ndb0:keys=3\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    assert_resp(
+        &mut stream,
+        &["INFO"],
+        &format!("${}\r\n{}\r\n", info.len(), info),
+    );
+
+    // Errors
+    assert_resp(
+        &mut stream,
+        &["FLUSHALL"],
+        "-ERR unknown command 'flushall'\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["GET"],
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SET", "key1", "value1", "NX", "XX"],
+        "-ERR syntax error\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SET", "key1", "value1", "EX", "soon"],
+        "-ERR value is not an integer or out of range\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SET", "key1", "value1", "PX", "0"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SET", "key1", "value1", "EX", "9223372036854775807"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SET", "key1", "value1", "PX", "9223372036854775807"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+
+    // Inline commands and pipelining
+    stream.write_all(b"PING\r\nGET key2\r\nEXISTS key2 key3\n")?;
+    let expected = "+PONG\r\n$3\r\nnew\r\n:2\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(String::from_utf8_lossy(&reply), expected);
+
+    assert_resp(&mut stream, &["QUIT"], "+OK\r\n");
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+
+    Ok(())
+}
+
+// SCAN walks all keys in batches, even while they are being changed.
+#[test]
+fn resp_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a1", "a2", "b1", "b2", "c1"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    let mut stream = TcpStream::connect(spawn_resp_server(store.clone()))?;
+
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "COUNT", "2"],
+        "*2\r\n$1\r\n1\r\n*2\r\n$2\r\na1\r\n$2\r\na2\r\n",
+    );
+    store.remove("b1".to_owned())?;
+    store.set("a3".to_owned(), "value".to_owned())?;
+    assert_resp(
+        &mut stream,
+        &["SCAN", "1", "COUNT", "2", "MATCH", "*2"],
+        "*2\r\n$1\r\n2\r\n*1\r\n$2\r\nb2\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SCAN", "2", "COUNT", "2"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$2\r\nc1\r\n",
+    );
+
+    // A cursor can only be resumed once
+    assert_resp(&mut stream, &["SCAN", "2"], "-ERR invalid cursor\r\n");
+    assert_resp(
+        &mut stream,
+        &["SCAN", "0", "COUNT", "0"],
+        "-ERR syntax error\r\n",
+    );
+
+    Ok(())
+}
+
+// A connection only remembers its 64 newest unfinished scans.
+#[test]
+fn resp_scan_forgets_oldest_cursor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in ["a1", "a2"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    let mut stream = TcpStream::connect(spawn_resp_server(store))?;
+
+    for cursor in 1..=65 {
+        let expected = format!(
+            "*2\r\n${}\r\n{}\r\n*1\r\n$2\r\na1\r\n",
+            cursor.to_string().len(),
+            cursor
+        );
+        assert_resp(&mut stream, &["SCAN", "0", "COUNT", "1"], &expected);
+    }
+    assert_resp(&mut stream, &["SCAN", "1"], "-ERR invalid cursor\r\n");
+    assert_resp(
+        &mut stream,
+        &["SCAN", "2"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$2\r\na2\r\n",
+    );
+    assert_resp(
+        &mut stream,
+        &["SCAN", "65"],
+        "*2\r\n$1\r\n0\r\n*1\r\n$2\r\na2\r\n",
+    );
+
+    Ok(())
+}
+
+// `kvs-server --protocol resp` serves the store to Redis clients, which the
+// memory engine does not support.
+#[test]
+fn cli_resp_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, addr) = start_server_binary(temp_dir.path(), &["--protocol", "resp"]);
+    let mut stream = TcpStream::connect(addr)?;
+    assert_resp(&mut stream, &["SET", "key1", "value1"], "+OK\r\n");
+    assert_resp(&mut stream, &["GET", "key1"], "$6\r\nvalue1\r\n");
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--protocol", "resp"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+fn spawn_http_server(store: KvStore) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || HttpServer::new(store).serve(listener));
+    addr
+}
+
+// Send one request on a new connection and return the status and body of the
+// response
+fn http_request(addr: &str, method: &str, target: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        target,
+        addr,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_owned())
+}
+
+// The HTTP API reads and writes the store, answering with JSON and with 404
+// for missing keys and 500 for the store's errors.
+#[test]
+fn http_api() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let addr = spawn_http_server(store.clone());
+
+    assert_eq!(
+        http_request(&addr, "PUT", "/keys/key1", r#"{"value":"value1"}"#),
+        (204, String::new())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        http_request(&addr, "GET", "/keys/key1", ""),
+        (200, r#"{"key":"key1","value":"value1"}"#.to_owned())
+    );
+    let (status, _) = http_request(&addr, "GET", "/keys/key2", "");
+    assert_eq!(status, 404);
+
+    // Keys are percent-decoded
+    http_request(&addr, "PUT", "/keys/a%20b%2Fc", r#"{"value":"x"}"#);
+    assert_eq!(store.get("a b/c".to_owned())?, Some("x".to_owned()));
+
+    store.set("prefix1".to_owned(), "1".to_owned())?;
+    store.set("prefix2".to_owned(), "2".to_owned())?;
+    assert_eq!(
+        http_request(&addr, "GET", "/keys?prefix=prefix", ""),
+        (
+            200,
+            r#"[{"key":"prefix1","value":"1"},{"key":"prefix2","value":"2"}]"#.to_owned()
+        )
+    );
+    let (_, body) = http_request(&addr, "GET", "/keys", "");
+    assert_eq!(body.matches("\"key\"").count(), 4);
+
+    assert_eq!(
+        http_request(&addr, "DELETE", "/keys/key1", ""),
+        (204, String::new())
+    );
+    let (status, _) = http_request(&addr, "DELETE", "/keys/key1", "");
+    assert_eq!(status, 404);
+
+    let (status, body) = http_request(&addr, "GET", "/stats", "");
+    assert_eq!(status, 200);
+    assert!(body.contains(r#""keys":3"#), "{}", body);
+
+    // Client errors
+    let (status, _) = http_request(&addr, "PUT", "/keys/key1", "value1");
+    assert_eq!(status, 400);
+    let (status, _) = http_request(&addr, "POST", "/keys/key1", "");
+    assert_eq!(status, 405);
+    let (status, _) = http_request(&addr, "GET", "/other", "");
+    assert_eq!(status, 404);
+
+    // A value the string API cannot return is the server's error
+    store.set_bytes(b"binary".to_vec(), vec![0xff])?;
+    let (status, body) = http_request(&addr, "GET", "/keys/binary", "");
+    assert_eq!(status, 500);
+    assert!(body.contains("error"), "{}", body);
+
+    Ok(())
+}
+
+// One connection carries several requests unless it asks to be closed.
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let addr = spawn_http_server(store);
+
+    let mut stream = TcpStream::connect(&addr)?;
+    stream.write_all(
+        b"GET /keys/key1 HTTP/1.1\r\n\r\nGET /keys/key2 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert_eq!(response.matches("HTTP/1.1 ").count(), 2);
+    assert!(
+        response.contains("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        response
+    );
+
+    Ok(())
+}
+
+// `kvs-server --http` serves the HTTP API next to the kvs protocol.
+#[test]
+fn cli_http_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let http_addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let (_server, addr) = start_server_binary(temp_dir.path(), &["--http", &http_addr]);
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        http_request(&http_addr, "GET", "/keys/key1", ""),
+        (200, r#"{"key":"key1","value":"value1"}"#.to_owned())
+    );
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--http", &http_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// Every job spawned on the pool runs, even after other jobs panicked.
+fn exercise_pool<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (sender, receiver) = mpsc::channel();
+    for i in 0..100 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(i).unwrap());
+    }
+    let mut received: Vec<i32> = receiver.iter().take(100).collect();
+    received.sort();
+    assert_eq!(received, (0..100).collect::<Vec<_>>());
+
+    for _ in 0..8 {
+        pool.spawn(|| panic!("a panicking job"));
+    }
+    for i in 0..10 {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(i).unwrap());
+    }
+    assert_eq!(receiver.iter().take(10).count(), 10);
+
+    Ok(())
+}
+
+#[test]
+fn thread_pools() -> Result<()> {
+    exercise_pool::<NaiveThreadPool>()?;
+    exercise_pool::<SharedQueueThreadPool>()?;
+    exercise_pool::<WorkStealingThreadPool>()?;
+    assert!(SharedQueueThreadPool::new(0).is_err());
+    assert!(WorkStealingThreadPool::new(0).is_err());
+    Ok(())
+}
+
+// Dropping a fixed pool waits for the jobs spawned on it.
+#[test]
+fn drop_thread_pool() -> Result<()> {
+    fn spawn_and_drop<P: ThreadPool>() -> Result<()> {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = P::new(3)?;
+        for _ in 0..20 {
+            let done = done.clone();
+            pool.spawn(move || {
+                thread::sleep(Duration::from_millis(5));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 20);
+        Ok(())
+    }
+
+    spawn_and_drop::<SharedQueueThreadPool>()?;
+    spawn_and_drop::<WorkStealingThreadPool>()
+}
+
+// The server runs its connections on any of the pools, with more clients
+// than the pool has threads.
+#[test]
+fn server_thread_pools() -> Result<()> {
+    fn exercise_server<P: ThreadPool>() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = KvsServer::with_pool(MemoryStore::new(), P::new(2)?);
+        thread::spawn(move || server.serve(listener));
+
+        let handles: Vec<_> = (0..8)
+            .map(|thread_id| {
+                thread::spawn(move || {
+                    let mut client = KvsClient::connect(addr).unwrap();
+                    for i in 0..10 {
+                        let key = format!("key{}-{}", thread_id, i);
+                        client.set(key.clone(), i.to_string()).unwrap();
+                        assert_eq!(client.get(key).unwrap(), Some(i.to_string()));
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        Ok(())
+    }
+
+    exercise_server::<NaiveThreadPool>()?;
+    exercise_server::<SharedQueueThreadPool>()?;
+    exercise_server::<WorkStealingThreadPool>()
+}
+
+// `kvs-server --pool` picks the thread pool.
+#[test]
+fn cli_thread_pools() {
+    for pool in ["naive", "shared-queue", "work-stealing"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (_server, addr) =
+            start_server_binary(temp_dir.path(), &["--pool", pool, "--threads", "2"]);
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set("key1".to_owned(), pool.to_owned()).unwrap();
+        assert_eq!(
+            client.get("key1".to_owned()).unwrap(),
+            Some(pool.to_owned())
+        );
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--pool", "shared-queue", "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// The async store reads and writes like the blocking one, from any number of
+// tasks at once.
+#[tokio::test]
+async fn async_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        store.get("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).await?, None);
+    store.remove("key1".to_owned()).await?;
+    assert!(matches!(
+        store.remove("key1".to_owned()).await,
+        Err(KvError::KeyNotFound(_))
+    ));
+
+    store.set_bytes(b"bytes".to_vec(), vec![0xff]).await?;
+    assert_eq!(store.get_bytes(b"bytes".to_vec()).await?, Some(vec![0xff]));
+    let mut batch = WriteBatch::new();
+    batch.set("a", "1");
+    batch.set("b", "2");
+    batch.remove("bytes");
+    store.apply_batch(batch).await?;
+    assert_eq!(store.store().keys().collect::<Vec<_>>(), [b"a", b"b"]);
+    store
+        .set_with_ttl(b"ttl".to_vec(), b"x".to_vec(), Duration::from_millis(20))
+        .await?;
+    assert!(store.store().metadata("ttl").unwrap().expires_at.is_some());
+
+    let tasks: Vec<_> = (0..50)
+        .map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let key = format!("key{}", i);
+                store.set(key.clone(), i.to_string()).await.unwrap();
+                assert_eq!(store.get(key).await.unwrap(), Some(i.to_string()));
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    store.sync().await?;
+    drop(store);
+
+    // Everything is on disk for the blocking store
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key49".to_owned())?, Some("49".to_owned()));
+
+    Ok(())
+}
+
+fn spawn_async_server(server: AsyncKvsServer<SharedQueueThreadPool>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    listener.set_nonblocking(true).unwrap();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            server.serve(listener).await
+        })
+    });
+    addr
+}
+
+// The async server answers `KvsClient` like the blocking one.
+#[test]
+fn async_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let async_store = AsyncKvStore::with_pool(store.clone(), SharedQueueThreadPool::new(2)?);
+    let addr = spawn_async_server(AsyncKvsServer::new(async_store).backup_dir(backup_dir.path()));
+
+    let mut client = KvsClient::connect(&addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvError::KeyNotFound(key)) if key == "key1"
+    ));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.backup("backup")?;
+    assert!(matches!(
+        client.backup("../backup"),
+        Err(KvError::Server(_))
+    ));
+    let backup = KvStore::open(backup_dir.path().join("backup"))?;
+    assert_eq!(backup.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // More clients than the pool has threads, all connected at once
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let addr = addr.clone();
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for i in 0..20 {
+                    let key = format!("key{}-{}", thread_id, i);
+                    client.set(key.clone(), i.to_string()).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(i.to_string()));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("key7-19".to_owned())?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+// `kvs-server --async` serves `kvs-client` on tokio.
+#[test]
+fn cli_async_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, addr) = start_server_binary(
+        temp_dir.path(),
+        &["--async", "--pool", "work-stealing", "--threads", "2"],
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--async", "--engine", "memory"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}